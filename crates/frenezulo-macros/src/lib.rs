@@ -2,13 +2,78 @@ extern crate proc_macro;
 use proc_macro::TokenStream;
 use quote::quote;
 
+fn json_string(value: &str) -> String {
+    let mut json = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// `"a, b"` as a JSON array of strings
+fn json_list(value: &str) -> String {
+    let items = value
+        .split(',')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .map(json_string)
+        .collect::<Vec<_>>();
+    format!("[{}]", items.join(","))
+}
+
+/// Builds the JSON manifest from `prefix = "..", timeout_ms = .., max_memory = .., max_fuel = .., capabilities = "a, b", methods = "GET, POST"`,
+/// `reuse = true, max_requests = .., max_lifetime_ms = ..`. Returns whether workers are reused as well.
+fn manifest(args: syn::AttributeArgs) -> syn::Result<(String, bool)> {
+    let mut fields = Vec::new();
+    let mut reuse = false;
+    for arg in args {
+        let pair = match arg {
+            syn::NestedMeta::Meta(syn::Meta::NameValue(pair)) => pair,
+            other => return Err(syn::Error::new_spanned(other, "expected `name = value`")),
+        };
+        let name = pair.path.get_ident().map(|ident| ident.to_string()).unwrap_or_default();
+        let value = match (name.as_str(), &pair.lit) {
+            ("prefix", syn::Lit::Str(value)) => json_string(&value.value()),
+            ("timeout_ms" | "max_memory" | "max_fuel" | "max_requests" | "max_lifetime_ms", syn::Lit::Int(value)) => value.base10_parse::<u64>()?.to_string(),
+            ("capabilities" | "methods", syn::Lit::Str(value)) => json_list(&value.value()),
+            ("reuse", syn::Lit::Bool(value)) => {
+                reuse = value.value;
+                reuse.to_string()
+            },
+            ("prefix" | "capabilities" | "methods", lit) => return Err(syn::Error::new_spanned(lit, "expected a string")),
+            ("timeout_ms" | "max_memory" | "max_fuel" | "max_requests" | "max_lifetime_ms", lit) => return Err(syn::Error::new_spanned(lit, "expected an integer")),
+            ("reuse", lit) => return Err(syn::Error::new_spanned(lit, "expected a boolean")),
+            _ => return Err(syn::Error::new_spanned(pair.path, "unknown manifest field, expected one of prefix, timeout_ms, max_memory, max_fuel, capabilities, methods, reuse, max_requests and max_lifetime_ms")),
+        };
+        fields.push(format!("{}:{}", json_string(&name), value));
+    }
+    Ok((format!("{{{}}}", fields.join(",")), reuse))
+}
+
+/// Turns `fn(Request) -> Response` into a frenezulo service.
+/// Arguments are embedded as the service's manifest, e.g. `#[frenezulo::handler(prefix = "hello", timeout_ms = 100, methods = "GET")]`.
+/// With `reuse = true` a worker handles requests until the host replaces it, instead of exiting after one.
 #[proc_macro_attribute]
-pub fn handler(_attr: TokenStream, item: TokenStream) -> TokenStream {
+pub fn handler(attr: TokenStream, item: TokenStream) -> TokenStream {
     let input: syn::ItemFn = match syn::parse(item.clone()) {
         Ok(it) => it,
         Err(e) => return token_stream_with_error(item, e),
     };
 
+    let args = syn::parse_macro_input!(attr as syn::AttributeArgs);
+    let (manifest, reuse) = match manifest(args) {
+        Ok(manifest) => manifest,
+        Err(e) => return token_stream_with_error(item, e),
+    };
+    let manifest_len = manifest.len();
+    let manifest = syn::LitByteStr::new(manifest.as_bytes(), proc_macro::Span::call_site().into());
+
     if input.sig.inputs.len() != 1 {
         let msg = "must be on a function with 1 argument of type Request";
         return syn::Error::new_spanned(&input.sig.ident, msg)
@@ -21,6 +86,13 @@ pub fn handler(_attr: TokenStream, item: TokenStream) -> TokenStream {
     let result = input.sig.output;
 
     quote! {
+        #[cfg_attr(target_arch = "wasm32", link_section = "frenezulo.manifest")]
+        #[used]
+        static __FRENEZULO_MANIFEST: [u8; #manifest_len] = *#manifest;
+
+        #[global_allocator]
+        static __FRENEZULO_ALLOCATOR: frenezulo::ReportingAllocator = frenezulo::ReportingAllocator;
+
         #[export_name = "frenezulo_main"]
         extern "C" fn frenezulo_main() {
             run(unsafe { lunatic::Mailbox::<frenezulo::WorkerMessage, frenezulo::WorkerSerializer>::new() })
@@ -31,16 +103,28 @@ pub fn handler(_attr: TokenStream, item: TokenStream) -> TokenStream {
             run(mailbox)
         }
 
+        /// reused workers keep their statics between requests, the host decides when to replace them
         fn run(mailbox: lunatic::Mailbox<frenezulo::WorkerMessage, frenezulo::WorkerSerializer>) {
-            match mailbox.receive() {
-                //MailboxResult::Message(msg) => match msg {
-                    frenezulo::WorkerMessage::Request(request_id, request, respond_to) => {
-                        let response = __handle(request);
-                        respond_to.send(frenezulo::ModuleSupervisorMessage::CompleteRequest(
-                            request_id, response,
-                        ));
-                    }
-                //},
+            loop {
+                match mailbox.receive() {
+                    //MailboxResult::Message(msg) => match msg {
+                        frenezulo::WorkerMessage::Request(request_id, request, respond_to) => {
+                            frenezulo::report_out_of_memory(request_id, respond_to.clone());
+                            let panic_to = respond_to.clone();
+                            std::panic::set_hook(Box::new(move |info| {
+                                eprintln!("{info}");
+                                panic_to.send(frenezulo::ModuleSupervisorMessage::Panicked(request_id));
+                            }));
+                            let response = __handle(request);
+                            respond_to.send(frenezulo::ModuleSupervisorMessage::CompleteRequest(
+                                request_id, response,
+                            ));
+                        }
+                    //},
+                }
+                if !#reuse {
+                    break;
+                }
             }
         }
 
@@ -1,6 +1,6 @@
 use frenezulo::{Response, ResponseMetadata, Request};
 
-#[frenezulo::handler]
+#[frenezulo::handler(prefix = "hello", methods = "GET")]
 fn handle(request: Request) -> Response {
     Response { metadata: ResponseMetadata { status: 200, version: request.metadata.version, headers: Default::default() },
         body: b"Hello World!".to_vec()
@@ -3,7 +3,7 @@ use lunatic::{supervisor::{Supervisor, SupervisorConfig, SupervisorStrategy}, Pr
 
 use crate::service_registry::{ServiceRegistryMessage, self};
 
-use crate::{router::Router, listener::Listener};
+use crate::{router::Router, listener::{Listener, ListenerRole}, config::Config};
 
 pub struct Application;
 
@@ -15,8 +15,8 @@ pub struct ServiceRegistryWrapper(Process<ServiceRegistryMessage>);
 #[abstract_process]
 impl ServiceRegistryWrapper {
     #[init]
-    fn init(_: ProcessRef<Self>, _: ()) -> Self {
-        let process = service_registry::start();
+    fn init(_: ProcessRef<Self>, config: Config) -> Self {
+        let process = service_registry::start(config);
         Self(process)
     }
 
@@ -32,16 +32,18 @@ impl ServiceRegistryWrapper {
 }
 
 impl Supervisor for Application {
-    type Arg = ();
+    type Arg = Config;
 
-    type Children = (ServiceRegistryWrapper, Router, Listener);
+    type Children = (ServiceRegistryWrapper, Router, Listener, Listener);
 
-    fn init(config: &mut SupervisorConfig<Self>, _: ()) {
-        config.set_strategy(SupervisorStrategy::OneForOne);
+    fn init(config: &mut SupervisorConfig<Self>, app_config: Config) {
+        // the router reloads the catalog into a fresh registry, neither holds ids the other doesn't know
+        config.set_strategy(SupervisorStrategy::OneForAll);
         config.children_args((
-            ((), None),
-            ((), Some("router".to_owned())),
-            ((), Some("listener".to_owned()))
+            (app_config.clone(), None),
+            (app_config.clone(), Some("router".to_owned())),
+            ((app_config.clone(), ListenerRole::Public), Some("listener".to_owned())),
+            ((app_config, ListenerRole::Admin), Some("admin_listener".to_owned()))
         ));
     }
 }
\ No newline at end of file
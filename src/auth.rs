@@ -0,0 +1,201 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha2::{Sha256, Digest};
+use submillisecond::http::{Method, Request};
+
+use crate::config::{AdminConfig, AdminClient, Access};
+
+/// How far the timestamp of a signed request may be off
+static MAX_CLOCK_SKEW_SECS : u64 = 300;
+
+pub enum AuthError {
+    /// 401, no or invalid credentials
+    Unauthenticated(String),
+    /// 403, valid credentials without the required access
+    Forbidden(String),
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// The signed string, `{method}\n{path and query}\n{timestamp}\n{hex sha256 of the body}`
+fn signing_payload(request: &Request<Vec<u8>>, timestamp: &str) -> String {
+    let path_and_query = request.uri().path_and_query().map_or("/", |p| p.as_str());
+    let body_hash = hex::encode(Sha256::digest(request.body()));
+    format!("{}\n{path_and_query}\n{timestamp}\n{body_hash}", request.method())
+}
+
+fn bearer<'a>(config: &'a AdminConfig, token: &str) -> Option<&'a AdminClient> {
+    config.clients.iter()
+        .find(|client| client.token.as_deref().map_or(false, |t| constant_time_eq(t.as_bytes(), token.trim().as_bytes())))
+}
+
+/// `Authorization: HMAC-SHA256 client=<name>,timestamp=<unix seconds>,signature=<hex>`
+fn signed<'a>(config: &'a AdminConfig, request: &Request<Vec<u8>>, params: &str) -> Result<&'a AdminClient, AuthError> {
+    let param = |name: &str| params.split(',')
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value);
+    let (name, timestamp, signature) = match (param("client"), param("timestamp"), param("signature")) {
+        (Some(name), Some(timestamp), Some(signature)) => (name, timestamp, signature),
+        _ => return Err(AuthError::Unauthenticated("Signature needs client, timestamp and signature".to_owned()))
+    };
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    match timestamp.parse::<u64>() {
+        Ok(timestamp) if timestamp.abs_diff(now) <= MAX_CLOCK_SKEW_SECS => (),
+        _ => return Err(AuthError::Unauthenticated("Signature timestamp is invalid or expired".to_owned()))
+    }
+
+    let client = config.clients.iter()
+        .find(|client| client.name == name)
+        .ok_or_else(|| AuthError::Unauthenticated("Invalid signature".to_owned()))?;
+    let key = client.hmac_key.as_deref()
+        .ok_or_else(|| AuthError::Unauthenticated("Invalid signature".to_owned()))?;
+    let signature = hex::decode(signature)
+        .map_err(|_| AuthError::Unauthenticated("Invalid signature".to_owned()))?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(signing_payload(request, timestamp).as_bytes());
+    mac.verify_slice(&signature)
+        .map_err(|_| AuthError::Unauthenticated("Invalid signature".to_owned()))?;
+    Ok(client)
+}
+
+pub fn authorize(config: &AdminConfig, request: &Request<Vec<u8>>) -> Result<(), AuthError> {
+    if config.clients.is_empty() {
+        return Ok(());
+    }
+
+    let authorization = request.headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| AuthError::Unauthenticated("Missing Authorization header".to_owned()))?;
+
+    let client = match authorization.split_once(' ') {
+        Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") =>
+            bearer(config, token).ok_or_else(|| AuthError::Unauthenticated("Invalid token".to_owned()))?,
+        Some((scheme, params)) if scheme.eq_ignore_ascii_case("hmac-sha256") => signed(config, request, params)?,
+        _ => return Err(AuthError::Unauthenticated("Unsupported Authorization scheme".to_owned()))
+    };
+
+    let required = match *request.method() {
+        Method::GET | Method::HEAD => Access::Read,
+        _ => Access::Write
+    };
+    if client.access < required {
+        return Err(AuthError::Forbidden(format!("Client {:?} has no write access", client.name)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static KEY : &str = "a-long-random-shared-secret";
+
+    fn config() -> AdminConfig {
+        AdminConfig { clients: vec![
+            AdminClient { name: "ci".to_owned(), token: Some("a-long-random-bearer-token".to_owned()), hmac_key: None, access: Access::Write },
+            AdminClient { name: "dashboard".to_owned(), token: Some("a-long-random-reading-token".to_owned()), hmac_key: None, access: Access::Read },
+            AdminClient { name: "deploy".to_owned(), token: None, hmac_key: Some(KEY.to_owned()), access: Access::Write },
+        ] }
+    }
+
+    fn request(method: Method, authorization: Option<&str>, body: &[u8]) -> Request<Vec<u8>> {
+        let mut builder = Request::builder().method(method).uri("/services/add?version=v2");
+        if let Some(authorization) = authorization {
+            builder = builder.header("authorization", authorization);
+        }
+        builder.body(body.to_vec()).unwrap()
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    fn signature(key: &str, method: &str, timestamp: u64, body: &[u8]) -> String {
+        let payload = format!("{method}\n/services/add?version=v2\n{timestamp}\n{}", hex::encode(Sha256::digest(body)));
+        let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).unwrap();
+        mac.update(payload.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn signed_request(scheme: &str, client: &str, key: &str, timestamp: u64, body: &[u8]) -> Request<Vec<u8>> {
+        let authorization = format!("{scheme} client={client},timestamp={timestamp},signature={}", signature(key, "POST", timestamp, body));
+        request(Method::POST, Some(&authorization), body)
+    }
+
+    fn unauthenticated(result: Result<(), AuthError>) -> bool {
+        matches!(result, Err(AuthError::Unauthenticated(_)))
+    }
+
+    #[test]
+    fn everything_is_allowed_without_clients() {
+        assert!(authorize(&AdminConfig::default(), &request(Method::POST, None, b"")).is_ok());
+    }
+
+    #[test]
+    fn requires_an_authorization_header() {
+        assert!(unauthenticated(authorize(&config(), &request(Method::GET, None, b""))));
+        assert!(unauthenticated(authorize(&config(), &request(Method::GET, Some("Basic Y2k6c2VjcmV0"), b""))));
+    }
+
+    #[test]
+    fn matches_bearer_tokens() {
+        let config = config();
+        assert!(authorize(&config, &request(Method::POST, Some("Bearer a-long-random-bearer-token"), b"")).is_ok());
+        assert!(authorize(&config, &request(Method::POST, Some("bearer a-long-random-bearer-token"), b"")).is_ok());
+        assert!(unauthenticated(authorize(&config, &request(Method::POST, Some("Bearer a-long-random-bearer-toke"), b""))));
+        assert!(unauthenticated(authorize(&config, &request(Method::POST, Some("Bearer "), b""))));
+    }
+
+    #[test]
+    fn read_access_only_allows_reading() {
+        let config = config();
+        assert!(authorize(&config, &request(Method::GET, Some("Bearer a-long-random-reading-token"), b"")).is_ok());
+        assert!(authorize(&config, &request(Method::HEAD, Some("Bearer a-long-random-reading-token"), b"")).is_ok());
+        assert!(matches!(
+            authorize(&config, &request(Method::POST, Some("Bearer a-long-random-reading-token"), b"")),
+            Err(AuthError::Forbidden(_))
+        ));
+        assert!(matches!(
+            authorize(&config, &request(Method::DELETE, Some("Bearer a-long-random-reading-token"), b"")),
+            Err(AuthError::Forbidden(_))
+        ));
+    }
+
+    #[test]
+    fn verifies_hmac_signatures() {
+        let config = config();
+        assert!(authorize(&config, &signed_request("HMAC-SHA256", "deploy", KEY, now(), b"module")).is_ok());
+        assert!(authorize(&config, &signed_request("hmac-sha256", "deploy", KEY, now(), b"module")).is_ok());
+        assert!(unauthenticated(authorize(&config, &signed_request("HMAC-SHA256", "deploy", "another-shared-secret", now(), b"module"))));
+        // clients without a key can't sign
+        assert!(unauthenticated(authorize(&config, &signed_request("HMAC-SHA256", "ci", KEY, now(), b"module"))));
+        assert!(unauthenticated(authorize(&config, &signed_request("HMAC-SHA256", "unknown", KEY, now(), b"module"))));
+    }
+
+    #[test]
+    fn signatures_cover_the_request() {
+        let config = config();
+        let timestamp = now();
+        let authorization = format!("HMAC-SHA256 client=deploy,timestamp={timestamp},signature={}", signature(KEY, "POST", timestamp, b"module"));
+        assert!(unauthenticated(authorize(&config, &request(Method::POST, Some(&authorization), b"another module"))));
+        assert!(unauthenticated(authorize(&config, &request(Method::PUT, Some(&authorization), b"module"))));
+        let without_signature = format!("HMAC-SHA256 client=deploy,timestamp={timestamp}");
+        assert!(unauthenticated(authorize(&config, &request(Method::POST, Some(&without_signature), b"module"))));
+    }
+
+    #[test]
+    fn signatures_expire() {
+        let config = config();
+        assert!(authorize(&config, &signed_request("HMAC-SHA256", "deploy", KEY, now() - MAX_CLOCK_SKEW_SECS + 5, b"")).is_ok());
+        assert!(authorize(&config, &signed_request("HMAC-SHA256", "deploy", KEY, now() + MAX_CLOCK_SKEW_SECS - 5, b"")).is_ok());
+        assert!(unauthenticated(authorize(&config, &signed_request("HMAC-SHA256", "deploy", KEY, now() - MAX_CLOCK_SKEW_SECS - 5, b""))));
+        assert!(unauthenticated(authorize(&config, &signed_request("HMAC-SHA256", "deploy", KEY, now() + MAX_CLOCK_SKEW_SECS + 5, b""))));
+    }
+}
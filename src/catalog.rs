@@ -0,0 +1,89 @@
+use std::{fs, io, path::PathBuf, collections::HashSet};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{config::{LimitOverrides, Capabilities}, routes::PathRewrite};
+
+/// One registered version of a service, `module` is the SHA-256 of the module stored next to the catalog
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CatalogEntry {
+    #[serde(default)]
+    pub host: Option<String>,
+    pub prefix: String,
+    pub version: String,
+    pub weight: u32,
+    pub module: String,
+    #[serde(default)]
+    pub limits: LimitOverrides,
+    #[serde(default)]
+    pub path: PathRewrite,
+    #[serde(default)]
+    pub capabilities: Capabilities,
+}
+
+pub struct Catalog {
+    dir: PathBuf,
+}
+
+impl Catalog {
+    pub fn new(dir: &str) -> Self {
+        Self { dir: PathBuf::from(dir) }
+    }
+
+    fn catalog_file(&self) -> PathBuf {
+        self.dir.join("catalog.json")
+    }
+
+    fn modules_dir(&self) -> PathBuf {
+        self.dir.join("modules")
+    }
+
+    fn module_file(&self, hash: &str) -> PathBuf {
+        self.modules_dir().join(format!("{hash}.wasm"))
+    }
+
+    pub fn store_module(&self, hash: &str, module_data: &[u8]) -> io::Result<()> {
+        let path = self.module_file(hash);
+        if path.exists() {
+            return Ok(());
+        }
+
+        fs::create_dir_all(self.modules_dir())?;
+        let temp = path.with_extension("tmp");
+        fs::write(&temp, module_data)?;
+        fs::rename(temp, path)
+    }
+
+    pub fn load_module(&self, hash: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.module_file(hash))
+    }
+
+    pub fn load(&self) -> io::Result<Vec<CatalogEntry>> {
+        match fs::read(self.catalog_file()) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Replaces the catalog and deletes all stored modules no entry refers to
+    pub fn save(&self, entries: &[CatalogEntry]) -> io::Result<()> {
+        fs::create_dir_all(self.modules_dir())?;
+        let path = self.catalog_file();
+        let temp = path.with_extension("tmp");
+        fs::write(&temp, serde_json::to_vec_pretty(entries)?)?;
+        fs::rename(temp, path)?;
+
+        let referenced = entries.iter().map(|entry| entry.module.as_str()).collect::<HashSet<_>>();
+        for file in fs::read_dir(self.modules_dir())? {
+            let path = file?.path();
+            let unreferenced = path.file_stem()
+                .and_then(|stem| stem.to_str())
+                .map_or(false, |hash| !referenced.contains(hash));
+            if unreferenced {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,376 @@
+use std::{collections::HashSet, net::SocketAddr, path::{Path, Component}};
+
+use anyhow::{anyhow, bail, Context};
+use serde::{Serialize, Deserialize};
+
+use frenezulo::Manifest;
+
+use crate::{router, routes::{Route, PathRewrite}, service_registry::ModuleInfo, signing, wasm};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub admin: AdminConfig,
+    pub signing: SigningConfig,
+    pub defaults: Limits,
+    /// the most any service may be granted
+    pub capabilities: Capabilities,
+    pub services: Vec<ServiceConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: String,
+    /// serves the management endpoints on their own address, instead of under `/services` of `bind`
+    pub admin_bind: Option<String>,
+    /// outer timeout of a request, only hit if a service never answers
+    pub request_timeout_ms: u64,
+    /// how long listeners use their copy of the routes before asking the router for changes
+    pub route_cache_ms: u64,
+    pub max_module_size: usize,
+    pub catalog: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: "0.0.0.0:3000".to_owned(),
+            admin_bind: None,
+            request_timeout_ms: 30_000,
+            route_cache_ms: 1_000,
+            max_module_size: 1024 * 1024 * 5,
+            catalog: "./catalog".to_owned(),
+        }
+    }
+}
+
+/// Clients allowed to use the management endpoints, if empty the endpoints are unprotected
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    pub clients: Vec<AdminClient>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AdminClient {
+    pub name: String,
+    /// authenticates with `Authorization: Bearer <token>`
+    pub token: Option<String>,
+    /// authenticates with HMAC-SHA256 signed requests, see auth.rs
+    pub hmac_key: Option<String>,
+    #[serde(default)]
+    pub access: Access,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    /// only GET requests
+    Read,
+    #[default]
+    Write,
+}
+
+/// Modules uploaded through the management endpoints need a signature by one of these keys, unless there are none
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SigningConfig {
+    /// hex encoded Ed25519 public keys
+    pub trusted_keys: Vec<String>,
+}
+
+/// Capabilities a manifest can require, `read_dirs` and `write_dirs` depend on the host and can only be granted
+pub static CAPABILITY_NAMES : [&str; 3] = ["spawn", "compile", "network"];
+
+/// What the workers of a service may do, nothing by default
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Capabilities {
+    pub spawn: bool,
+    pub compile: bool,
+    pub network: bool,
+    /// host directories preopened for WASI
+    pub read_dirs: Vec<String>,
+    pub write_dirs: Vec<String>,
+}
+
+/// `dir` is one of `dirs` or inside of one
+fn within(dirs: &[String], dir: &str) -> bool {
+    let dir = Path::new(dir);
+    !dir.components().any(|component| component == Component::ParentDir)
+        && dirs.iter().any(|allowed| dir.starts_with(allowed))
+}
+
+impl Capabilities {
+    /// Lunatic 0.11 can't restrict the network access of a process, nor preopen a directory read-only,
+    /// so granting either is rejected instead of pretending it is enforced
+    pub fn supported(&self) -> Result<(), String> {
+        if self.network {
+            return Err("Capability network is not supported, network access of workers can't be controlled".to_owned());
+        }
+        if !self.read_dirs.is_empty() {
+            return Err("read_dirs are not supported, directories can only be preopened read-write with write_dirs".to_owned());
+        }
+        Ok(())
+    }
+
+    /// Checks nothing beyond `allowed` is granted
+    pub fn check(&self, allowed: &Capabilities) -> Result<(), String> {
+        self.supported()?;
+        for (name, granted, permitted) in [("spawn", self.spawn, allowed.spawn), ("compile", self.compile, allowed.compile)] {
+            if granted && !permitted {
+                return Err(format!("Capability {name} is not allowed"));
+            }
+        }
+        if let Some(dir) = self.write_dirs.iter().find(|dir| !within(&allowed.write_dirs, dir)) {
+            return Err(format!("Directory {dir:?} is not allowed to be written"));
+        }
+        Ok(())
+    }
+
+    /// Checks everything the manifest requires is granted
+    pub fn require(&self, required: &[String]) -> Result<(), String> {
+        for name in required {
+            let granted = match name.as_str() {
+                "spawn" => self.spawn,
+                "compile" => self.compile,
+                "network" => return Err("Module requires capability network, which is not supported".to_owned()),
+                _ => false
+            };
+            if !granted {
+                return Err(format!("Module requires capability {name}, which is not granted"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn dirs(&self) -> impl Iterator<Item = &String> {
+        self.write_dirs.iter()
+    }
+}
+
+/// Limits of a service's workers
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub timeout_ms: u64,
+    pub max_memory: u64,
+    /// compute budget of a worker in lunatic fuel units, unlimited if unset
+    pub max_fuel: Option<u64>,
+    /// idle workers kept ready for requests, the pool grows up to `pool_max` under load
+    pub pool_min: usize,
+    pub pool_max: usize,
+    /// a worker of a module with `reuse = true` is replaced after this many requests, or once it is older
+    pub max_requests: u64,
+    pub max_lifetime_ms: u64,
+    /// requests handled at once, further requests wait in a queue of `queue_size` for at most `queue_timeout_ms`
+    pub max_concurrency: usize,
+    pub queue_size: usize,
+    pub queue_timeout_ms: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 30,
+            max_memory: 1024 * 1024 * 4,
+            max_fuel: None,
+            pool_min: 1,
+            pool_max: 8,
+            max_requests: 1000,
+            max_lifetime_ms: 60_000,
+            max_concurrency: 64,
+            queue_size: 256,
+            queue_timeout_ms: 1_000,
+        }
+    }
+}
+
+impl Limits {
+    pub fn check(&self) -> Result<(), String> {
+        if self.timeout_ms == 0 {
+            return Err("timeout_ms has to be above 0".to_owned());
+        }
+        if self.max_memory == 0 {
+            return Err("max_memory has to be above 0".to_owned());
+        }
+        if self.max_fuel == Some(0) {
+            return Err("max_fuel has to be above 0".to_owned());
+        }
+        if self.max_requests == 0 || self.max_lifetime_ms == 0 {
+            return Err("max_requests and max_lifetime_ms have to be above 0".to_owned());
+        }
+        if self.max_concurrency == 0 || self.queue_timeout_ms == 0 {
+            return Err("max_concurrency and queue_timeout_ms have to be above 0".to_owned());
+        }
+        if self.pool_min > self.pool_max {
+            return Err(format!("pool_min ({}) can't be above pool_max ({})", self.pool_min, self.pool_max));
+        }
+        Ok(())
+    }
+}
+
+/// Per-service overrides of the default limits
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitOverrides {
+    pub timeout_ms: Option<u64>,
+    pub max_memory: Option<u64>,
+    pub max_fuel: Option<u64>,
+    pub pool_min: Option<usize>,
+    pub pool_max: Option<usize>,
+    pub max_requests: Option<u64>,
+    pub max_lifetime_ms: Option<u64>,
+    pub max_concurrency: Option<usize>,
+    pub queue_size: Option<usize>,
+    pub queue_timeout_ms: Option<u64>,
+}
+
+impl From<&Manifest> for LimitOverrides {
+    fn from(manifest: &Manifest) -> Self {
+        Self {
+            timeout_ms: manifest.timeout_ms,
+            max_memory: manifest.max_memory,
+            max_fuel: manifest.max_fuel,
+            pool_min: None,
+            pool_max: None,
+            max_requests: manifest.max_requests,
+            max_lifetime_ms: manifest.max_lifetime_ms,
+            max_concurrency: None,
+            queue_size: None,
+            queue_timeout_ms: None,
+        }
+    }
+}
+
+impl LimitOverrides {
+    /// Takes values missing in `self` from `other`
+    pub fn or(self, other: LimitOverrides) -> Self {
+        Self {
+            timeout_ms: self.timeout_ms.or(other.timeout_ms),
+            max_memory: self.max_memory.or(other.max_memory),
+            max_fuel: self.max_fuel.or(other.max_fuel),
+            pool_min: self.pool_min.or(other.pool_min),
+            pool_max: self.pool_max.or(other.pool_max),
+            max_requests: self.max_requests.or(other.max_requests),
+            max_lifetime_ms: self.max_lifetime_ms.or(other.max_lifetime_ms),
+            max_concurrency: self.max_concurrency.or(other.max_concurrency),
+            queue_size: self.queue_size.or(other.queue_size),
+            queue_timeout_ms: self.queue_timeout_ms.or(other.queue_timeout_ms),
+        }
+    }
+
+    pub fn resolve(&self, defaults: &Limits) -> Limits {
+        Limits {
+            timeout_ms: self.timeout_ms.unwrap_or(defaults.timeout_ms),
+            max_memory: self.max_memory.unwrap_or(defaults.max_memory),
+            max_fuel: self.max_fuel.or(defaults.max_fuel),
+            pool_min: self.pool_min.unwrap_or(defaults.pool_min),
+            pool_max: self.pool_max.unwrap_or(defaults.pool_max),
+            max_requests: self.max_requests.unwrap_or(defaults.max_requests),
+            max_lifetime_ms: self.max_lifetime_ms.unwrap_or(defaults.max_lifetime_ms),
+            max_concurrency: self.max_concurrency.unwrap_or(defaults.max_concurrency),
+            queue_size: self.queue_size.unwrap_or(defaults.queue_size),
+            queue_timeout_ms: self.queue_timeout_ms.unwrap_or(defaults.queue_timeout_ms),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceConfig {
+    /// only serves requests to this host, `*.` matches all subdomains
+    pub host: Option<String>,
+    /// can be empty if `host` is set
+    #[serde(default)]
+    pub prefix: String,
+    /// path of the module, relative to the working directory
+    pub module: String,
+    pub version: Option<String>,
+    pub weight: Option<u32>,
+    #[serde(default)]
+    pub limits: LimitOverrides,
+    /// `{ strip_prefix = true }` or `{ rewrite = "/v2/{path}" }`
+    #[serde(default)]
+    pub path: PathRewrite,
+    #[serde(default)]
+    pub capabilities: Capabilities,
+}
+
+fn validate_limits(limits: &Limits) -> anyhow::Result<()> {
+    limits.check().map_err(|e| anyhow!(e))
+}
+
+impl Config {
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {path:?}"))?;
+        let config : Config = toml::from_str(&text)
+            .with_context(|| format!("failed to parse config file {path:?}"))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.server.bind.parse::<SocketAddr>()
+            .with_context(|| format!("server.bind {:?} is not a valid socket address", self.server.bind))?;
+        if let Some(admin_bind) = &self.server.admin_bind {
+            admin_bind.parse::<SocketAddr>()
+                .with_context(|| format!("server.admin_bind {admin_bind:?} is not a valid socket address"))?;
+            if *admin_bind == self.server.bind {
+                bail!("server.admin_bind has to differ from server.bind");
+            }
+        }
+        if self.server.request_timeout_ms == 0 {
+            bail!("server.request_timeout_ms has to be above 0");
+        }
+        validate_limits(&self.defaults).context("invalid defaults")?;
+        self.capabilities.supported().map_err(|e| anyhow!(e)).context("invalid capabilities")?;
+
+        let mut names = HashSet::new();
+        for client in &self.admin.clients {
+            let context = || format!("invalid admin client {:?}", client.name);
+            if !names.insert(client.name.as_str()) {
+                return Err(anyhow!("client is configured more than once")).with_context(context);
+            }
+            match (&client.token, &client.hmac_key) {
+                (Some(secret), None) | (None, Some(secret)) if secret.len() >= 16 => (),
+                (Some(_), None) | (None, Some(_)) => return Err(anyhow!("secret has to be at least 16 characters long")).with_context(context),
+                _ => return Err(anyhow!("exactly one of token and hmac_key has to be set")).with_context(context),
+            }
+        }
+
+        for (index, key) in self.signing.trusted_keys.iter().enumerate() {
+            signing::parse_key(key).with_context(|| format!("invalid signing.trusted_keys #{}", index + 1))?;
+        }
+
+        let mut seen = HashSet::new();
+        // services often share a module, it is only compiled once
+        let mut compiled = HashSet::new();
+        for (index, service) in self.services.iter().enumerate() {
+            let context = || format!("invalid service #{} ({:?})", index + 1, service.prefix);
+            let route = Route::new(service.host.as_deref(), &service.prefix);
+            router::check_route(&route, self.server.admin_bind.is_none())
+                .map_err(|e| anyhow!(e))
+                .with_context(context)?;
+            service.path.check().map_err(|e| anyhow!(e)).with_context(context)?;
+            service.capabilities.check(&self.capabilities).map_err(|e| anyhow!(e)).with_context(context)?;
+            if !seen.insert((route, service.version.as_deref())) {
+                return Err(anyhow!("service is configured more than once")).with_context(context);
+            }
+            let module_data = std::fs::read(&service.module)
+                .with_context(|| format!("failed to read module {:?}", service.module))
+                .with_context(context)?;
+            let manifest = wasm::validate(&module_data).with_context(context)?;
+            if compiled.insert(ModuleInfo::new(&module_data).hash) {
+                wasm::compile(&module_data).with_context(context)?;
+            }
+            service.capabilities.require(&manifest.capabilities).map_err(|e| anyhow!(e)).with_context(context)?;
+            validate_limits(&service.limits.or(LimitOverrides::from(&manifest)).resolve(&self.defaults)).with_context(context)?;
+        }
+        Ok(())
+    }
+}
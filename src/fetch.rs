@@ -0,0 +1,347 @@
+use std::{fmt, fs::File, io::{self, BufRead, BufReader, Read, Write}, time::{Duration, Instant}};
+
+use lunatic::net::TcpStream;
+use submillisecond::http::Uri;
+
+static MAX_REDIRECTS : usize = 5;
+static MAX_LINE_LENGTH : u64 = 8 * 1024;
+static MAX_HEADERS : usize = 100;
+static CONNECT_TIMEOUT : Duration = Duration::from_secs(5);
+/// a single read of the response may stall for this long
+static READ_TIMEOUT : Duration = Duration::from_secs(10);
+/// the whole download, including redirects, has to finish in this time
+static FETCH_TIMEOUT : Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub enum FetchError {
+    /// the source is not a valid URL
+    InvalidSource(String),
+    UnsupportedScheme(String),
+    /// a `file://` source could not be read
+    File(String, io::Error),
+    Connect(String, io::Error),
+    Io(io::Error),
+    /// the remote answered with something that is not HTTP
+    InvalidResponse(String),
+    /// the remote answered with a status other than 200
+    Status(u16),
+    TooManyRedirects,
+    /// the remote redirected to a location that is not a http URL
+    UnsupportedRedirect(String),
+    TooLarge(usize),
+    /// the source stopped sending, or the download took longer than `FETCH_TIMEOUT`
+    Timeout,
+}
+
+impl FetchError {
+    /// status code of the management response
+    pub fn status(&self) -> u16 {
+        match self {
+            FetchError::InvalidSource(_) | FetchError::UnsupportedScheme(_) | FetchError::File(_, _) => 400,
+            FetchError::TooLarge(_) => 413,
+            FetchError::Connect(_, _) | FetchError::Io(_) | FetchError::InvalidResponse(_)
+                | FetchError::Status(_) | FetchError::TooManyRedirects | FetchError::UnsupportedRedirect(_) => 502,
+            FetchError::Timeout => 504,
+        }
+    }
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::InvalidSource(reason) => write!(f, "Invalid source: {reason}"),
+            FetchError::UnsupportedScheme(scheme) => write!(f, "Unsupported source scheme {scheme:?}, expected http or file"),
+            FetchError::File(path, e) => write!(f, "Failed to read {path:?}: {e}"),
+            FetchError::Connect(host, e) => write!(f, "Failed to connect to {host}: {e}"),
+            FetchError::Io(e) => write!(f, "Failed to download module: {e}"),
+            FetchError::InvalidResponse(reason) => write!(f, "Invalid response from source: {reason}"),
+            FetchError::Status(status) => write!(f, "Source returned status {status}"),
+            FetchError::TooManyRedirects => write!(f, "Source redirected more than {MAX_REDIRECTS} times"),
+            FetchError::UnsupportedRedirect(location) => write!(f, "Source redirected to {location:?}, only http redirects are supported"),
+            FetchError::TooLarge(max_size) => write!(f, "No service module > {max_size} bytes allowed"),
+            FetchError::Timeout => write!(f, "Source timed out, downloads have to finish within {}s", FETCH_TIMEOUT.as_secs()),
+        }
+    }
+}
+
+impl From<io::Error> for FetchError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => FetchError::Timeout,
+            _ => FetchError::Io(e)
+        }
+    }
+}
+
+/// Fails reads once the deadline passed, so a source can't keep the download going by sending slowly
+struct DeadlineReader<R> {
+    inner: R,
+    deadline: Instant,
+}
+
+impl<R: Read> Read for DeadlineReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if Instant::now() >= self.deadline {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.inner.read(buf)
+    }
+}
+
+enum HttpResult {
+    Body(Vec<u8>),
+    Redirect(String),
+}
+
+/// Downloads a module from a `http://` or `file://` source, never reading more than `max_size` bytes of it
+pub fn fetch(source: &str, max_size: usize) -> Result<Vec<u8>, FetchError> {
+    // Uri does not accept the empty authority of file:///path
+    if let Some(path) = source.strip_prefix("file://") {
+        return read_file(path, max_size);
+    }
+
+    let deadline = Instant::now() + FETCH_TIMEOUT;
+    let mut source = source.to_owned();
+    for redirects in 0..=MAX_REDIRECTS {
+        // a bad redirect is the fault of the source, not of the caller
+        let uri = match source.parse::<Uri>() {
+            Ok(uri) if redirects > 0 && uri.scheme_str() != Some("http") => return Err(FetchError::UnsupportedRedirect(source)),
+            Ok(uri) => uri,
+            Err(_) if redirects > 0 => return Err(FetchError::UnsupportedRedirect(source)),
+            Err(e) => return Err(FetchError::InvalidSource(e.to_string()))
+        };
+        let result = match uri.scheme_str() {
+            Some("http") => http_get(&uri, max_size, deadline)?,
+            Some(scheme) => return Err(FetchError::UnsupportedScheme(scheme.to_owned())),
+            None => return Err(FetchError::InvalidSource("missing scheme".to_owned()))
+        };
+
+        match result {
+            HttpResult::Body(module_data) => return Ok(module_data),
+            HttpResult::Redirect(location) => {
+                source = resolve(&uri, &location);
+            }
+        }
+    }
+    Err(FetchError::TooManyRedirects)
+}
+
+/// Reads at most `max_size` bytes, failing if there are more
+fn read_limited(reader: impl Read, max_size: usize) -> Result<Vec<u8>, FetchError> {
+    let mut data = Vec::new();
+    reader.take(max_size as u64 + 1).read_to_end(&mut data)?;
+    if data.len() > max_size {
+        return Err(FetchError::TooLarge(max_size));
+    }
+    Ok(data)
+}
+
+/// `file:///path` is absolute, `file://./path` is relative to the working directory
+fn read_file(path: &str, max_size: usize) -> Result<Vec<u8>, FetchError> {
+    let path = path.strip_prefix("localhost").unwrap_or(path);
+    if !path.starts_with('/') && !path.starts_with("./") {
+        return Err(FetchError::InvalidSource(format!("file sources can't have a host, got {path:?}")));
+    }
+
+    let file = File::open(path).map_err(|e| FetchError::File(path.to_owned(), e))?;
+    match read_limited(file, max_size) {
+        Err(FetchError::Io(e)) => Err(FetchError::File(path.to_owned(), e)),
+        result => result
+    }
+}
+
+/// Relative redirect locations are resolved against the current source
+fn resolve(base: &Uri, location: &str) -> String {
+    if location.contains("://") {
+        return location.to_owned();
+    }
+    // protocol relative, keeps the scheme but not the host
+    if location.starts_with("//") {
+        return format!("{}:{location}", base.scheme_str().unwrap_or("http"));
+    }
+
+    let authority = base.authority().map_or("", |authority| authority.as_str());
+    if location.starts_with('/') {
+        format!("http://{authority}{location}")
+    }
+    else {
+        let directory = base.path().rsplit_once('/').map_or("", |(directory, _)| directory);
+        format!("http://{authority}{directory}/{location}")
+    }
+}
+
+fn read_line(reader: &mut impl BufRead) -> Result<String, FetchError> {
+    let mut line = String::new();
+    reader.by_ref().take(MAX_LINE_LENGTH).read_line(&mut line)?;
+    if !line.ends_with('\n') {
+        return Err(FetchError::InvalidResponse("line too long or connection closed".to_owned()));
+    }
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+fn http_get(uri: &Uri, max_size: usize, deadline: Instant) -> Result<HttpResult, FetchError> {
+    let host = uri.host().ok_or_else(|| FetchError::InvalidSource("missing host".to_owned()))?;
+    let port = uri.port_u16().unwrap_or(80);
+    let address = format!("{host}:{port}");
+    let mut stream = TcpStream::connect_timeout(address.clone(), CONNECT_TIMEOUT)
+        .map_err(|e| FetchError::Connect(address.clone(), e))?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    stream.set_write_timeout(Some(READ_TIMEOUT))?;
+
+    let path_query = uri.path_and_query().map_or("/", |path_query| path_query.as_str());
+    let host_header = uri.authority().map_or(host, |authority| authority.as_str());
+    stream.write_all(format!(
+        "GET {path_query} HTTP/1.1\r\nHost: {host_header}\r\nUser-Agent: frenezulo\r\nAccept: application/wasm, */*\r\nConnection: close\r\n\r\n"
+    ).as_bytes())?;
+    stream.flush()?;
+
+    let mut reader = BufReader::new(DeadlineReader { inner: stream, deadline });
+
+    let status_line = read_line(&mut reader)?;
+    let status = match status_line.split(' ').collect::<Vec<_>>()[..] {
+        [version, status, ..] if version.starts_with("HTTP/1.") =>
+            status.parse::<u16>().map_err(|_| FetchError::InvalidResponse(format!("invalid status line {status_line:?}")))?,
+        _ => return Err(FetchError::InvalidResponse(format!("invalid status line {status_line:?}")))
+    };
+
+    let mut content_length : Option<usize> = None;
+    let mut chunked = false;
+    let mut location : Option<String> = None;
+    for header_count in 0.. {
+        let line = read_line(&mut reader)?;
+        if line.is_empty() {
+            break;
+        }
+        if header_count == MAX_HEADERS {
+            return Err(FetchError::InvalidResponse("too many headers".to_owned()));
+        }
+
+        let (name, value) = line.split_once(':')
+            .ok_or_else(|| FetchError::InvalidResponse(format!("invalid header {line:?}")))?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = Some(value.parse::<usize>()
+                .map_err(|_| FetchError::InvalidResponse(format!("invalid Content-Length {value:?}")))?);
+        }
+        else if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = value.split(',').any(|encoding| encoding.trim().eq_ignore_ascii_case("chunked"));
+        }
+        else if name.eq_ignore_ascii_case("location") {
+            location = Some(value.to_owned());
+        }
+    }
+
+    match (status, location) {
+        (301 | 302 | 303 | 307 | 308, Some(location)) => return Ok(HttpResult::Redirect(location)),
+        (200, _) => (),
+        (status, _) => return Err(FetchError::Status(status))
+    }
+
+    let module_data = if chunked {
+        read_chunked(&mut reader, max_size)?
+    }
+    else if let Some(content_length) = content_length {
+        if content_length > max_size {
+            return Err(FetchError::TooLarge(max_size));
+        }
+        let mut module_data = vec![0; content_length];
+        reader.read_exact(&mut module_data)?;
+        module_data
+    }
+    else {
+        read_limited(reader, max_size)?
+    };
+    Ok(HttpResult::Body(module_data))
+}
+
+fn read_chunked(reader: &mut impl BufRead, max_size: usize) -> Result<Vec<u8>, FetchError> {
+    let mut module_data = Vec::new();
+    loop {
+        let line = read_line(reader)?;
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| FetchError::InvalidResponse(format!("invalid chunk size {size:?}")))?;
+        if size == 0 {
+            break;
+        }
+        // module_data never exceeds max_size, and a huge chunk size must not overflow
+        if size > max_size - module_data.len() {
+            return Err(FetchError::TooLarge(max_size));
+        }
+
+        let start = module_data.len();
+        module_data.resize(start + size, 0);
+        reader.read_exact(&mut module_data[start..])?;
+        if !read_line(reader)?.is_empty() {
+            return Err(FetchError::InvalidResponse("chunk is longer than its size".to_owned()));
+        }
+    }
+
+    // skip trailers
+    while !read_line(reader)?.is_empty() {}
+    Ok(module_data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunked(body: &[u8], max_size: usize) -> Result<Vec<u8>, FetchError> {
+        read_chunked(&mut &body[..], max_size)
+    }
+
+    #[test]
+    fn resolves_redirect_locations() {
+        let base = "http://modules.example:8080/services/hello.wasm".parse::<Uri>().unwrap();
+        assert_eq!(resolve(&base, "http://cdn.example/hello.wasm"), "http://cdn.example/hello.wasm");
+        assert_eq!(resolve(&base, "https://cdn.example/hello.wasm"), "https://cdn.example/hello.wasm");
+        assert_eq!(resolve(&base, "//cdn.example/hello.wasm"), "http://cdn.example/hello.wasm");
+        assert_eq!(resolve(&base, "/v2/hello.wasm"), "http://modules.example:8080/v2/hello.wasm");
+        assert_eq!(resolve(&base, "hello-v2.wasm"), "http://modules.example:8080/services/hello-v2.wasm");
+    }
+
+    #[test]
+    fn blames_the_source_for_bad_redirects() {
+        assert_eq!(FetchError::UnsupportedRedirect("https://cdn.example/hello.wasm".to_owned()).status(), 502);
+        assert_eq!(FetchError::UnsupportedScheme("https".to_owned()).status(), 400);
+    }
+
+    #[test]
+    fn reads_chunks_and_skips_trailers() {
+        let body = b"4\r\n\0asm\r\n4;ext=1\r\n\x01\0\0\0\r\n0\r\nTrailer: x\r\n\r\n";
+        assert_eq!(chunked(body, 8).unwrap(), b"\0asm\x01\0\0\0");
+    }
+
+    #[test]
+    fn rejects_oversized_bodies() {
+        let body = b"4\r\nabcd\r\n5\r\nefghi\r\n0\r\n\r\n";
+        assert!(matches!(chunked(body, 8), Err(FetchError::TooLarge(8))));
+        assert_eq!(chunked(body, 9).unwrap(), b"abcdefghi");
+    }
+
+    #[test]
+    fn rejects_chunk_sizes_that_would_overflow() {
+        let body = format!("4\r\nabcd\r\n{:x}\r\n", usize::MAX);
+        assert!(matches!(chunked(body.as_bytes(), 8), Err(FetchError::TooLarge(8))));
+    }
+
+    #[test]
+    fn rejects_truncated_bodies() {
+        let body = b"4\r\nabcd\r\n0\r\n\r\n";
+        for len in 0..body.len() {
+            assert!(chunked(&body[..len], 8).is_err(), "accepted body truncated to {len} bytes");
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_chunks() {
+        assert!(matches!(chunked(b"zz\r\n", 8), Err(FetchError::InvalidResponse(_))));
+        assert!(matches!(chunked(b"2\r\nabcd\r\n0\r\n\r\n", 8), Err(FetchError::InvalidResponse(_))));
+    }
+
+    #[test]
+    fn rejects_overlong_lines() {
+        let body = format!("{}1\r\na\r\n0\r\n\r\n", "0".repeat(MAX_LINE_LENGTH as usize));
+        assert!(matches!(chunked(body.as_bytes(), 8), Err(FetchError::InvalidResponse(_))));
+    }
+}
@@ -2,7 +2,9 @@ use lunatic::{Tag, Process};
 use serde::{Serialize, Deserialize};
 
 mod http;
+mod memory;
 pub use http::*;
+pub use memory::{ReportingAllocator, report_out_of_memory};
 pub use frenezulo_macros::handler;
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Hash)]
@@ -17,7 +19,11 @@ pub struct RequestId {
 
 #[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ModuleSupervisorMessage {
-    CompleteRequest(crate::RequestId, crate::http::Response)
+    CompleteRequest(crate::RequestId, crate::http::Response),
+    /// sent before a worker aborts because of a panic, to tell it apart from other traps
+    Panicked(crate::RequestId),
+    /// sent before a worker aborts because an allocation failed, see `ReportingAllocator`
+    OutOfMemory(crate::RequestId),
 }
 
 pub type WorkerSerializer = lunatic::serializer::MessagePack;
@@ -26,3 +32,26 @@ pub type WorkerSerializer = lunatic::serializer::MessagePack;
 pub enum WorkerMessage {
     Request(RequestId, crate::http::Request, Process<ModuleSupervisorMessage, WorkerSerializer>),
 }
+
+/// Name of the custom section `#[frenezulo::handler(...)]` embeds the manifest in
+pub static MANIFEST_SECTION : &str = "frenezulo.manifest";
+
+/// Configuration a module declares for itself, stored as JSON in the `frenezulo.manifest` section.
+/// Values configured by the operator take precedence.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+#[serde(default)]
+pub struct Manifest {
+    /// prefix used if none is given when adding the service
+    pub prefix: Option<String>,
+    pub timeout_ms: Option<u64>,
+    pub max_memory: Option<u64>,
+    pub max_fuel: Option<u64>,
+    pub capabilities: Vec<String>,
+    /// HTTP methods the service accepts, all if empty
+    pub methods: Vec<String>,
+    /// workers handle requests in a loop instead of exiting after one
+    pub reuse: bool,
+    /// requests and lifetime after which a reused worker is replaced
+    pub max_requests: Option<u64>,
+    pub max_lifetime_ms: Option<u64>,
+}
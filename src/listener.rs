@@ -1,112 +1,458 @@
-use std::{time::Duration, io::{Write, Read, BufReader, BufRead}};
+use std::{time::Duration, collections::HashMap, fmt, str::FromStr};
 
-use lunatic::{abstract_process, process::ProcessRef, Tag, Process, Mailbox, spawn_link, net::TcpStream};
+use lunatic::{abstract_process, process::ProcessRef, Tag, Process, Mailbox};
 use serde::{Serialize, Deserialize};
-use submillisecond::{Application, RequestContext, http::{Response, Uri}, Handler, Json, extract::FromRequest};
-use anyhow::anyhow;
+use submillisecond::{Application, RequestContext, http::{Response, Method, HeaderValue, Uri, request, uri::PathAndQuery}, Handler, Json, extract::FromRequest};
 
-use crate::{service_registry::{self}, router};
+use ed25519_dalek::PublicKey;
+use frenezulo::Manifest;
 
-pub struct Listener(Process<()>);
+use crate::{service_registry::{self, ServiceInfo}, router::{self, Backend, RouteTarget, ServiceOptions}, routes::{self, Route, PathRewrite}, route_cache, multipart, fetch, config::{Config, AdminConfig, SigningConfig, LimitOverrides, Capabilities}, auth::{self, AuthError}, signing};
 
-#[derive(Serialize, Deserialize, Clone, Copy)]
-struct AppHandler {
+static VERSION_HEADER : &str = "x-frenezulo-version";
+static VERSION_COOKIE : &str = "frenezulo-version";
+static SIGNATURE_HEADER : &str = "x-frenezulo-signature";
+/// original path and query of requests to services with a path rewrite
+static FORWARDED_URI_HEADER : &str = "x-forwarded-uri";
+static FORWARDED_PREFIX_HEADER : &str = "x-forwarded-prefix";
+
+/// Not running if it is the admin listener and no `server.admin_bind` is configured
+pub struct Listener(Option<Process<()>>);
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenerRole {
+    /// serves the services on `server.bind`, and the management endpoints unless they have their own address
+    Public,
+    /// serves only the management endpoints on `server.admin_bind`
+    Admin,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct AppHandler {
+    request_timeout_ms: u64,
+    route_cache_ms: u64,
+    max_module_size: usize,
+    admin: AdminConfig,
+    signing: SigningConfig,
+    serve_admin: bool,
+    serve_services: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct ServiceAdd {
-    prefix: String,
     source: String,
+    #[serde(flatten)]
+    service: ServiceUpload,
 }
 
-fn service_add(request: &mut RequestContext) -> anyhow::Result<Response<Vec<u8>>> {
-    println!("service_add");
-    let data = Json::<ServiceAdd>::from_request(request)?.0;
-    let prefix = data.prefix;
-
-    let parsed_source = data.source.parse::<Uri>()?;
-
-    let host = parsed_source.host().map_or_else(|| Err(anyhow!("Source had no host")), |e| Ok(e))?;
-    let port = parsed_source.port_u16().or(Some(80)).unwrap();
-    let full_host = format!("{host}:{port:?}");
-    println!("{full_host}");
-    let mut stream = TcpStream::connect_timeout(full_host.clone(), Duration::from_secs(5))?;
-
-    let path_query = parsed_source.path_and_query().map_or_else(|| Err(anyhow!("no path or query")), |m| Ok(m))?.as_str();
-    stream.write_all(format!("GET {path_query} HTTP/1.0\r\n").as_bytes())?;
-    stream.write_all(b"User-Agent: Custom-Lunatic/0.0\r\n")?;
-    stream.write_all(b"Connection: keep-alive\r\n")?;
-    stream.write_all(format!("Host: {full_host}").as_bytes())?;
-    stream.write_all(b"\r\n")?;
-
-    // no body
-    stream.write_all(b"\r\n")?;
-    
-    stream.flush()?;
-    println!("request out");
-    let mut reader = BufReader::new(stream.clone());
-    
-    let mut start_line = String::new();
-    reader.read_line(&mut start_line)?;
-    println!("read {start_line}");
-    if start_line != "HTTP/1.0 200 OK\r\n" {
-        return Err(anyhow!("Remote returned non-ok or non-HTTP/1.0 response"));
-    }
-
-    let mut content_length : usize = 0;
-    // skip all response headers, we don't care
-    loop {
-        let mut line = String::new();
-        reader.read_line(&mut line)?;
-        if line == "\r\n" {
-            break
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ServiceUpload {
+    /// only serves requests to this host, `*.` matches all subdomains
+    host: Option<String>,
+    /// taken from the module's manifest if missing
+    prefix: Option<String>,
+    version: Option<String>,
+    weight: Option<u32>,
+    /// hex encoded Ed25519 signature over the module
+    signature: Option<String>,
+    /// take precedence over the manifest and `[defaults]`
+    #[serde(default)]
+    limits: LimitOverrides,
+    #[serde(default)]
+    path: PathRewrite,
+    /// nothing is granted if missing
+    #[serde(default)]
+    capabilities: Capabilities,
+}
+
+fn content_type(request: &RequestContext) -> Option<String> {
+    request.headers()
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_owned())
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let escaped = match bytes[index] {
+            b'%' => value.get(index + 1..index + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None
+        };
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                index += 3;
+            },
+            None => {
+                decoded.push(if bytes[index] == b'+' { b' ' } else { bytes[index] });
+                index += 1;
+            }
         }
-        if let Some(("Content-Length:", content_length_string)) = line.split_once(':') {
-            content_length = content_length_string.parse::<usize>()?;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn query_param(request: &RequestContext, name: &str) -> Option<String> {
+    request.uri().query()?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| percent_decode(value))
+}
+
+/// `Host` header, or the host of the URI for HTTP/2 requests
+fn request_host(request: &RequestContext) -> Option<String> {
+    request.headers()
+        .get("host")
+        .and_then(|value| value.to_str().ok())
+        .or_else(|| request.uri().host())
+        .map(|host| host.to_owned())
+}
+
+/// version a client pinned itself to, by header or cookie
+fn pinned_version(request: &RequestContext) -> Option<String> {
+    if let Some(version) = request.headers().get(VERSION_HEADER).and_then(|value| value.to_str().ok()) {
+        return Some(version.to_owned());
+    }
+
+    request.headers()
+        .get_all("cookie")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == VERSION_COOKIE)
+        .map(|(_, version)| version.to_owned())
+}
+
+fn plain_response(request: &RequestContext, status: u16, body: impl Into<Vec<u8>>) -> Response<Vec<u8>> {
+    Response::builder()
+        .version(request.version())
+        .status(status)
+        .body(body.into()).expect("response builder has to succeed")
+}
+
+fn register(request: &RequestContext, trusted_keys: &[PublicKey], service: ServiceUpload, module_data: Vec<u8>, origin: &str) -> Response<Vec<u8>> {
+    let signature = match service.signature.map(hex::decode).transpose() {
+        Ok(signature) => signature,
+        Err(e) => return plain_response(request, 400, format!("Invalid signature: {e}"))
+    };
+
+    let len = module_data.len();
+    let options = ServiceOptions {
+        version: service.version,
+        weight: service.weight,
+        limits: service.limits,
+        path: service.path,
+        capabilities: service.capabilities
+    };
+    match router::add_service(trusted_keys, service.host, service.prefix, options, module_data, signature) {
+        Ok((route, _)) => plain_response(request, 200, format!("OK.\n Added Service {route} from {origin} with size: {len}")),
+        Err(e) => plain_response(request, 400, e)
+    }
+}
+
+fn register_upload(request: &RequestContext, max_module_size: usize, trusted_keys: &[PublicKey], service: ServiceUpload, module_data: Vec<u8>) -> Response<Vec<u8>> {
+    if module_data.len() > max_module_size {
+        return plain_response(request, 413, format!("No service module > {max_module_size} bytes allowed"));
+    }
+
+    register(request, trusted_keys, service, module_data, "upload")
+}
+
+fn limit_param<T: FromStr>(request: &RequestContext, name: &str) -> Result<Option<T>, String> where T::Err: fmt::Display {
+    query_param(request, name)
+        .map(|value| value.parse::<T>().map_err(|e| format!("Invalid {name}: {e}")))
+        .transpose()
+}
+
+fn limit_params(request: &RequestContext) -> Result<LimitOverrides, String> {
+    Ok(LimitOverrides {
+        timeout_ms: limit_param(request, "timeout_ms")?,
+        max_memory: limit_param(request, "max_memory")?,
+        max_fuel: limit_param(request, "max_fuel")?,
+        pool_min: limit_param(request, "pool_min")?,
+        pool_max: limit_param(request, "pool_max")?,
+        max_requests: limit_param(request, "max_requests")?,
+        max_lifetime_ms: limit_param(request, "max_lifetime_ms")?,
+        max_concurrency: limit_param(request, "max_concurrency")?,
+        queue_size: limit_param(request, "queue_size")?,
+        queue_timeout_ms: limit_param(request, "queue_timeout_ms")?,
+    })
+}
+
+/// `?capabilities=spawn,compile`, directories can only be granted in the JSON body or metadata
+fn capability_params(request: &RequestContext) -> Result<Capabilities, String> {
+    let mut capabilities = Capabilities::default();
+    for name in query_param(request, "capabilities").unwrap_or_default().split(',').map(str::trim).filter(|name| !name.is_empty()) {
+        match name {
+            "spawn" => capabilities.spawn = true,
+            "compile" => capabilities.compile = true,
+            "network" => capabilities.network = true,
+            _ => return Err(format!("Unknown capability {name:?}, expected spawn, compile or network"))
         }
     }
-    println!("read content length {content_length:?}");
+    Ok(capabilities)
+}
 
-    if content_length > 1024 * 1024 * 5 {
-        return Err(anyhow!("No service module > 5mb allowed"))
+fn service_upload(request: &mut RequestContext, max_module_size: usize, trusted_keys: &[PublicKey], prefix: &str) -> anyhow::Result<Response<Vec<u8>>> {
+    match content_type(request).as_deref().map(|c| c.split(';').next().unwrap_or("").trim()) {
+        Some("application/wasm") => {
+            let module_data = request.body().clone();
+            let version = query_param(request, "version");
+            let weight = match query_param(request, "weight").map(|weight| weight.parse::<u32>()).transpose() {
+                Ok(weight) => weight,
+                Err(e) => return Ok(plain_response(request, 400, format!("Invalid weight: {e}")))
+            };
+            let limits = match limit_params(request) {
+                Ok(limits) => limits,
+                Err(e) => return Ok(plain_response(request, 400, e))
+            };
+            let signature = request.headers()
+                .get(SIGNATURE_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_owned());
+            let host = query_param(request, "host");
+            let path = PathRewrite {
+                strip_prefix: query_param(request, "strip_prefix").map_or(false, |strip| strip == "true"),
+                rewrite: query_param(request, "rewrite")
+            };
+            let capabilities = match capability_params(request) {
+                Ok(capabilities) => capabilities,
+                Err(e) => return Ok(plain_response(request, 400, e))
+            };
+            let service = ServiceUpload { host, prefix: Some(prefix.to_owned()), version, weight, signature, limits, path, capabilities };
+            Ok(register_upload(request, max_module_size, trusted_keys, service, module_data))
+        },
+        _ => Ok(plain_response(request, 415, "Expected an application/wasm body"))
     }
+}
+
+fn service_add_multipart(request: &mut RequestContext, max_module_size: usize, trusted_keys: &[PublicKey], boundary: &str) -> anyhow::Result<Response<Vec<u8>>> {
+    let parts = match multipart::parse(request.body(), boundary) {
+        Ok(parts) => parts,
+        Err(e) => return Ok(plain_response(request, 400, format!("Invalid multipart body: {e}")))
+    };
 
-    let mut module_data = Vec::<u8>::with_capacity(content_length);
-    if content_length > 0 {
-        reader.read_exact(&mut module_data)?;
+    let metadata = parts.iter().find(|part| part.name.as_deref() == Some("metadata"));
+    let module = parts.iter().find(|part| part.name.as_deref() == Some("module"));
+    let (metadata, module) = match (metadata, module) {
+        (Some(metadata), Some(module)) => (metadata, module),
+        _ => return Ok(plain_response(request, 400, "Expected a \"metadata\" and a \"module\" part"))
+    };
+    match module.content_type.as_deref().map(|c| c.split(';').next().unwrap_or("").trim()) {
+        Some("application/wasm" | "application/octet-stream") => (),
+        _ => return Ok(plain_response(request, 415, "Expected the \"module\" part to be application/wasm or application/octet-stream"))
     }
-    else {
-        reader.read_to_end(&mut module_data)?;
+
+    let metadata = match serde_json::from_slice::<ServiceUpload>(metadata.body) {
+        Ok(metadata) => metadata,
+        Err(e) => return Ok(plain_response(request, 400, format!("Invalid metadata: {e}")))
+    };
+    let module_data = module.body.to_vec();
+
+    Ok(register_upload(request, max_module_size, trusted_keys, metadata, module_data))
+}
+
+fn service_add(request: &mut RequestContext, max_module_size: usize, trusted_keys: &[PublicKey]) -> anyhow::Result<Response<Vec<u8>>> {
+    println!("service_add");
+    if let Some(content_type) = content_type(request) {
+        if let Some(boundary) = multipart::boundary(&content_type) {
+            return service_add_multipart(request, max_module_size, trusted_keys, boundary);
+        }
     }
-    println!("read all data");
-    let len = module_data.len();
 
-    router::add_service(prefix.clone(), module_data);
+    let data = match Json::<ServiceAdd>::from_request(request) {
+        Ok(data) => data.0,
+        Err(e) => return Ok(plain_response(request, 400, format!("Invalid request: {e}")))
+    };
 
+    let module_data = match fetch::fetch(&data.source, max_module_size) {
+        Ok(module_data) => module_data,
+        Err(e) => {
+            println!("{e}");
+            return Ok(plain_response(request, e.status(), e.to_string()));
+        }
+    };
+
+    let origin = format!("source {:?}", data.source);
+    Ok(register(request, trusted_keys, data.service, module_data, &origin))
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct ServiceListing {
+    host: Option<String>,
+    prefix: String,
+    version: String,
+    weight: u32,
+    /// limits set when registering the service
+    limits: LimitOverrides,
+    capabilities: Capabilities,
+    manifest: Manifest,
+    #[serde(flatten)]
+    info: ServiceInfo,
+}
+
+fn service_listings(services: Vec<(Route, Backend)>) -> Vec<ServiceListing> {
+    let service_ids = services.iter().map(|(_, backend)| backend.service_id).collect();
+    services.into_iter()
+        .zip(service_registry::query_services(service_ids))
+        .filter_map(|((route, backend), info)| info.map(|info| ServiceListing {
+            host: route.host,
+            prefix: route.prefix,
+            version: backend.version,
+            weight: backend.weight,
+            limits: backend.limits,
+            capabilities: backend.capabilities,
+            manifest: backend.manifest,
+            info
+        }))
+        .collect()
+}
+
+fn json_response(request: &RequestContext, status: u16, value: &impl Serialize) -> anyhow::Result<Response<Vec<u8>>> {
     Ok(Response::builder()
     .version(request.version())
-    .status(200)
-    .body(format!("OK.\n Added Service {prefix:?} from remote {full_host:?} with size: {len}").as_bytes().to_vec())?)
-}
-
-fn service_handler(request: &mut RequestContext) -> Response<Vec<u8>> {
-    match request.uri().path() {
-        "/services/add" => {
-            service_add(request)
-                .unwrap_or_else(|e| {
-                    println!("{e}");
-                    Response::builder()
-                    .version(request.version())
-                    .status(503)
-                    .body(vec![]).expect("503 builder has to succeed")
-                })
-        }
-        _ => Response::builder()
-                    .version(request.version())
-                    .status(404)
-                    .body(vec![]).expect("404 builder has to succeed")
+    .status(status)
+    .header("Content-Type", "application/json")
+    .body(serde_json::to_vec(value)?)?)
+}
+
+/// `?host=` only lists the routes of that host
+fn service_list(request: &mut RequestContext) -> anyhow::Result<Response<Vec<u8>>> {
+    let host = query_param(request, "host").map(|host| routes::canonical_host(&host));
+    let services = router::list_services()
+        .into_iter()
+        .filter(|(route, _)| host.is_none() || route.host == host)
+        .collect();
+    let listings = service_listings(services);
+    json_response(request, 200, &listings)
+}
+
+/// The route addressed by `prefix` and the optional `?host=`
+fn route(request: &RequestContext, prefix: &str) -> Route {
+    Route::new(query_param(request, "host").as_deref(), prefix)
+}
+
+fn service_get(request: &mut RequestContext, prefix: &str) -> anyhow::Result<Response<Vec<u8>>> {
+    let route = route(request, prefix);
+    let backends = router::get_service(route.clone())
+        .into_iter()
+        .map(|backend| (route.clone(), backend))
+        .collect::<Vec<_>>();
+    let listings = service_listings(backends);
+
+    if listings.is_empty() {
+        return Ok(plain_response(request, 404, format!("Unknown Service {route}")));
+    }
+    json_response(request, 200, &listings)
+}
+
+fn service_delete(request: &mut RequestContext, prefix: &str) -> anyhow::Result<Response<Vec<u8>>> {
+    let route = route(request, prefix);
+    let version = query_param(request, "version");
+    let removed = router::remove_service(route.clone(), version);
+    if removed.is_empty() {
+        return Ok(plain_response(request, 404, format!("Unknown Service {route}")));
+    }
+    Ok(plain_response(request, 200, format!("OK.\n Deleted Service {route} {removed:?}")))
+}
+
+fn service_set_weights(request: &mut RequestContext, prefix: &str) -> anyhow::Result<Response<Vec<u8>>> {
+    let weights = match serde_json::from_slice::<HashMap<String, u32>>(request.body()) {
+        Ok(weights) => weights,
+        Err(e) => return Ok(plain_response(request, 400, format!("Invalid weights: {e}")))
+    };
+
+    let route = route(request, prefix);
+    match router::set_weights(route.clone(), weights) {
+        Ok(()) => Ok(plain_response(request, 200, format!("OK.\n Updated weights of Service {route}"))),
+        Err(e) => Ok(plain_response(request, 400, e))
+    }
+}
+
+fn service_handler(request: &mut RequestContext, admin: &AdminConfig, signing_config: &SigningConfig, max_module_size: usize) -> Response<Vec<u8>> {
+    match auth::authorize(admin, &request.request) {
+        Ok(()) => (),
+        Err(AuthError::Unauthenticated(reason)) => {
+            let mut response = plain_response(request, 401, reason);
+            response.headers_mut().insert("www-authenticate", HeaderValue::from_static("Bearer"));
+            return response;
+        },
+        Err(AuthError::Forbidden(reason)) => return plain_response(request, 403, reason)
     }
+
+    let trusted_keys = signing_config.trusted_keys.iter()
+        .map(|key| signing::parse_key(key).expect("keys are validated when loading the config"))
+        .collect::<Vec<_>>();
+    let path = request.uri().path().trim_end_matches('/').to_owned();
+    let method = request.method().clone();
+    let result = match (method, path.strip_prefix("/services")) {
+        (Method::POST, Some("/add")) => service_add(request, max_module_size, &trusted_keys),
+        (Method::GET, Some("")) => service_list(request),
+        // routes without a prefix, serving a whole `?host=`
+        (Method::POST, Some("")) => service_upload(request, max_module_size, &trusted_keys, ""),
+        (Method::DELETE, Some("")) => service_delete(request, ""),
+        (Method::PATCH, Some("")) => service_set_weights(request, ""),
+        (Method::POST, Some(rest)) => match rest.strip_prefix('/') {
+            Some(prefix) if !prefix.is_empty() => service_upload(request, max_module_size, &trusted_keys, prefix),
+            _ => Ok(not_found(request)),
+        },
+        (Method::GET, Some(rest)) => match rest.strip_prefix('/') {
+            Some(prefix) => service_get(request, prefix),
+            _ => Ok(not_found(request)),
+        },
+        (Method::DELETE, Some(rest)) => match rest.strip_prefix('/') {
+            Some(prefix) => service_delete(request, prefix),
+            _ => Ok(not_found(request)),
+        },
+        (Method::PATCH, Some(rest)) => match rest.strip_prefix('/') {
+            Some(prefix) => service_set_weights(request, prefix),
+            _ => Ok(not_found(request)),
+        },
+        _ => Ok(not_found(request)),
+    };
+
+    result.unwrap_or_else(|e| {
+        println!("{e}");
+        Response::builder()
+        .version(request.version())
+        .status(503)
+        .body(vec![]).expect("503 builder has to succeed")
+    })
+}
+
+/// Applies the service's path rewrite, the original path and query are kept in `X-Forwarded-Uri`
+fn rewrite_request(request: &mut request::Parts, target: &RouteTarget) -> Result<(), String> {
+    if target.path.is_identity() {
+        return Ok(());
+    }
+
+    let original = request.uri.path_and_query().map_or("/", |path_and_query| path_and_query.as_str()).to_owned();
+    let path = target.path.apply(&target.route.prefix, request.uri.path());
+    let path_and_query = match request.uri.query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path
+    };
+
+    let mut uri = request.uri.clone().into_parts();
+    uri.path_and_query = Some(path_and_query.parse::<PathAndQuery>().map_err(|e| format!("Invalid rewritten path {path_and_query:?}: {e}"))?);
+    request.uri = Uri::from_parts(uri).map_err(|e| format!("Invalid rewritten URI: {e}"))?;
+
+    if let Ok(value) = HeaderValue::from_str(&original) {
+        request.headers.insert(FORWARDED_URI_HEADER, value);
+    }
+    if let Ok(value) = HeaderValue::from_str(&format!("/{}", target.route.prefix)) {
+        request.headers.insert(FORWARDED_PREFIX_HEADER, value);
+    }
+    Ok(())
+}
+
+fn not_found(request: &RequestContext) -> Response<Vec<u8>> {
+    plain_response(request, 404, vec![])
 }
 
 impl Handler for AppHandler {
@@ -114,51 +460,70 @@ impl Handler for AppHandler {
         // let mailbox : Mailbox<crate::http::Response> = context.mailbox;
         let mailbox : Mailbox<crate::http::Response> = unsafe { Mailbox::new() };
 
-        let path = context.uri().path();
-        let prefix = match path.split_once('/') {
-            Some(("", rest)) => match rest.split_once('/') {
-                Some((prefix, _rest)) => Some(prefix),
-                _ => Some(rest)
-            },
-            Some((prefix, _rest)) => Some(prefix),
-            _ => None
-        };
+        let pinned = pinned_version(&context);
+        let host = request_host(&context);
+        let path = context.uri().path().to_owned();
+        let first_segment = path.split('/').find(|segment| !segment.is_empty());
         
-        let response = match prefix {
-            Some("services") => {
-                service_handler(&mut context).into()
+        let response = match first_segment {
+            Some(segment) if segment == router::ADMIN_PREFIX && self.serve_admin => {
+                service_handler(&mut context, &self.admin, &self.signing, self.max_module_size).into()
             }
-            Some(prefix) => match router::create_request(prefix.to_owned()) {
-                Some((service_id, request_id)) => {
+            _ if !self.serve_services => not_found(&context),
+            _ => match route_cache::route(host.as_deref(), &path, pinned.as_deref(), Duration::from_millis(self.route_cache_ms)) {
+                Some(target) if !target.methods.is_empty() && !target.methods.iter().any(|method| method.as_str() == context.method().as_str()) => {
+                    let mut response = plain_response(&context, 405, "Method Not Allowed");
+                    if let Ok(value) = HeaderValue::from_str(&target.methods.join(", ")) {
+                        response.headers_mut().insert("allow", value);
+                    }
+                    response
+                },
+                Some(target) => {
+                    let version = target.version.clone();
+                    let cookie_path = format!("/{}", target.route.prefix);
                     let request = context.request;
-                    let (m, b) = request.into_parts();
+                    let (mut m, b) = request.into_parts();
+                    if let Err(e) = rewrite_request(&mut m, &target) {
+                        println!("{e}");
+                        return Response::builder()
+                            .version(m.version)
+                            .status(500)
+                            .body(e.into_bytes()).expect("500 builder has to succeed");
+                    }
                     let req = frenezulo::Request
                     {
                         metadata: m.into(),
                         body: serde_bytes::ByteBuf::from(b.as_slice().to_vec())
                     };
                     
-                    service_registry::start_request(request_id, service_id, req, Process::this());
-                    match mailbox.receive_timeout(Duration::from_secs(30)) {
+                    service_registry::start_request(target.request_id, target.service_id, req, Process::this());
+                    let mut response : Response<Vec<u8>> = match mailbox.receive_timeout(Duration::from_millis(self.request_timeout_ms)) {
                         lunatic::MailboxResult::Message(response) => response.into(),
                         lunatic::MailboxResult::DeserializationFailed(_) => todo!(),
                         lunatic::MailboxResult::TimedOut =>
                             Response::builder()
                                 .status(408)
-                                .body(b"Outer 30s timeout has been hit. This should never happen.".to_vec())
+                                .body(format!("Outer {}ms timeout has been hit. This should never happen.", self.request_timeout_ms).into_bytes())
                                 .expect("Timeout builder has to succeed"),
                         lunatic::MailboxResult::LinkDied(_) => todo!(),
+                    };
+
+                    if let Ok(value) = HeaderValue::from_str(&version) {
+                        response.headers_mut().insert(VERSION_HEADER, value);
+                    }
+                    // keep clients of split routes on the version they were given
+                    if target.split && pinned.is_none() {
+                        if let Ok(value) = HeaderValue::from_str(&format!("{VERSION_COOKIE}={version}; Path={cookie_path}")) {
+                            response.headers_mut().append("set-cookie", value);
+                        }
                     }
+                    response
                 },
                 None => Response::builder()
                         .version(context.version())
                         .status(404)
                         .body(b"Unknown Service".to_vec()).expect("404 builder has to succeed")
             },
-            None => Response::builder()
-                    .version(context.version())
-                    .status(404)
-                    .body(b"Path did not include service prefix".to_vec()).expect("404 builder has to succeed")
         };
 
         // for testing: restart requests after each HTTP request
@@ -171,16 +536,36 @@ impl Handler for AppHandler {
 #[abstract_process]
 impl Listener {
     #[init]
-    fn init(_: ProcessRef<Self>, _: ()) -> Self {
-        let process = spawn_link!(|| {
-            Application::new(AppHandler { }).serve("0.0.0.0:3000").expect("Server has to start");
+    fn init(_: ProcessRef<Self>, (config, role): (Config, ListenerRole)) -> Self {
+        let separate_admin = config.server.admin_bind.is_some();
+        let bind = match role {
+            ListenerRole::Public => config.server.bind,
+            ListenerRole::Admin => match config.server.admin_bind {
+                Some(admin_bind) => admin_bind,
+                None => return Self(None)
+            }
+        };
+        let handler = AppHandler {
+            request_timeout_ms: config.server.request_timeout_ms,
+            route_cache_ms: config.server.route_cache_ms,
+            max_module_size: config.server.max_module_size,
+            admin: config.admin,
+            signing: config.signing,
+            serve_admin: role == ListenerRole::Admin || !separate_admin,
+            serve_services: role == ListenerRole::Public,
+        };
+        println!("{role:?} listener on {bind}");
+        let process = Process::spawn_link((handler, bind), |(handler, bind), _: Mailbox<()>| {
+            Application::new(handler).serve(bind.as_str()).expect("Server has to start");
         });
-        Self(process)
+        Self(Some(process))
     }
 
     #[terminate]
     fn terminate(self) {
-        self.0.kill()
+        if let Some(process) = self.0 {
+            process.kill()
+        }
     }
 
     #[handle_link_trapped]
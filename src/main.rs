@@ -1,11 +1,21 @@
 use lunatic::{Mailbox, process::StartProcess};
-use crate::application::Application;
+use crate::{application::Application, config::Config};
 mod http;
 mod module_supervisor;
 mod service_registry;
 mod listener;
 mod router;
 mod application;
+mod multipart;
+mod catalog;
+mod config;
+mod auth;
+mod signing;
+mod fetch;
+mod wasm;
+mod trie;
+mod routes;
+mod route_cache;
 
 /*
 fn index() -> &'static str {
@@ -53,23 +63,32 @@ fn handler(req: RequestContext) -> Response<Vec<u8>> {
         .expect("builder has to succeed")
 }*/
 
-fn start_app() {
-    Application::start_link((), None);
-    router::add_service("test1".to_owned(), std::fs::read("./test.wasm").expect("File has to exist"));
-    router::add_service("test2".to_owned(), std::fs::read("./test.wasm").expect("File has to exist"));
-    router::add_service("test3".to_owned(), std::fs::read("./test.wasm").expect("File has to exist"));
-    router::add_service("test4".to_owned(), std::fs::read("./test.wasm").expect("File has to exist"));
-    router::add_service("test5".to_owned(), std::fs::read("./test.wasm").expect("File has to exist"));
+fn start_app(config: &Config) {
+    Application::start_link(config.clone(), None);
+}
+
+fn load_config() -> Config {
+    match std::env::args().nth(1) {
+        Some(path) => Config::load(&path).unwrap_or_else(|e| {
+            eprintln!("Invalid configuration: {e:#}");
+            std::process::exit(1);
+        }),
+        None => Config::default()
+    }
 }
 
 #[lunatic::main]
 fn main(mailbox: Mailbox<()>) {
-    start_app();
+    let config = load_config();
+    if config.admin.clients.is_empty() {
+        println!("No admin clients configured, the /services endpoints are unprotected");
+    }
+    start_app(&config);
 
     let failure_mailbox = mailbox.catch_link_failure();
     loop {
         let message = failure_mailbox.receive();
         assert!(message.is_link_died());
-        start_app();
+        start_app(&config);
     }
 }
\ No newline at end of file
@@ -0,0 +1,50 @@
+use std::{alloc::{GlobalAlloc, Layout, System}, sync::{Mutex, atomic::{AtomicBool, Ordering}}};
+
+use lunatic::Process;
+
+use crate::{ModuleSupervisorMessage, RequestId, WorkerSerializer};
+
+/// Where a failed allocation is reported, set for every request by `#[frenezulo::handler]`
+static REPORT_TO : Mutex<Option<(RequestId, Process<ModuleSupervisorMessage, WorkerSerializer>)>> = Mutex::new(None);
+/// sending the report may allocate, that must not report again
+static REPORTED : AtomicBool = AtomicBool::new(false);
+
+/// Global allocator of service modules, installed by `#[frenezulo::handler]`.
+/// Failing to allocate aborts the worker without running the panic hook, so the failure is reported before,
+/// to tell exceeding the memory limit apart from other traps.
+pub struct ReportingAllocator;
+
+unsafe impl GlobalAlloc for ReportingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        reported(System.alloc(layout))
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        reported(System.alloc_zeroed(layout))
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        reported(System.realloc(ptr, layout, new_size))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+fn reported(ptr: *mut u8) -> *mut u8 {
+    if ptr.is_null() && !REPORTED.swap(true, Ordering::Relaxed) {
+        if let Ok(report_to) = REPORT_TO.try_lock() {
+            if let Some((request_id, process)) = report_to.as_ref() {
+                process.send(ModuleSupervisorMessage::OutOfMemory(*request_id));
+            }
+        }
+    }
+    ptr
+}
+
+/// Failed allocations are reported to `process` until the next request
+pub fn report_out_of_memory(request_id: RequestId, process: Process<ModuleSupervisorMessage, WorkerSerializer>) {
+    *REPORT_TO.lock().expect("report target has to be lockable") = Some((request_id, process));
+    REPORTED.store(false, Ordering::Relaxed);
+}
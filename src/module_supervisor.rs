@@ -1,136 +1,467 @@
-use std::{collections::HashMap, time::Duration};
+use std::{collections::{HashMap, VecDeque}, time::{Duration, Instant}};
 
 use frenezulo::{WorkerMessage, Version, ResponseMetadata, WorkerSerializer};
 use lunatic::{WasmModule, Process, LunaticError, ProcessConfig, Tag, Mailbox};
 use multimap::MultiMap;
 use serde::{Serialize, Deserialize};
 
-use crate::{service_registry::ServiceRegistryMessage};
+use crate::{service_registry::ServiceRegistryMessage, config::{Limits, Capabilities}, wasm};
 use frenezulo::{ ServiceId, RequestId, Request, Response};
 
-pub struct ModuleSupervisor {
+/// How often pools that had enough idle workers shrink by one worker, down to `pool_min`
+static POOL_SHRINK_INTERVAL : Duration = Duration::from_secs(10);
+
+/// A worker process, identified by the tag it was spawned with
+struct Worker {
+    tag: Tag,
+    process: Process<WorkerMessage, WorkerSerializer>,
+    /// requests completed, only reused workers serve more than one
+    served: u64,
+    spawned_at: Instant,
+}
+
+/// Idle workers of a service, ready to receive a `WorkerMessage::Request`, and requests waiting for a worker
+struct Pool {
+    limits: Limits,
+    config: ProcessConfig,
+    idle: Vec<Worker>,
+    /// requests being handled, at most `max_concurrency`
+    active: usize,
+    /// at most `queue_size` requests waiting until one of the active requests finished
+    queue: VecDeque<(RequestId, Request)>,
+    /// idle workers to keep, between `pool_min` and `pool_max`
+    target: usize,
+    /// a request found no idle worker since the last shrink
+    missed: bool,
+}
+
+/// A request a worker is handling
+struct Outstanding {
     service_id: ServiceId,
+    worker: Worker,
+    limits: Limits,
+    /// HTTP version of the request, for error responses
+    version: Version,
+    /// the worker reported a panic before it died
+    panicked: bool,
+    /// the worker reported a failed allocation before it died
+    out_of_memory: bool,
+}
+
+/// Supervises the workers of one module, shared by all services using the same module
+pub struct ModuleSupervisor {
     module: WasmModule,
+    /// the module's manifest opted in to workers serving many requests
+    reuse: bool,
+    services: HashMap<ServiceId, Pool>,
     supervisor: Process<ServiceRegistryMessage>,
-    outstanding_requests: HashMap<RequestId, Process<WorkerMessage, WorkerSerializer>>,
+    outstanding_requests: HashMap<RequestId, Outstanding>,
+    /// worker tag -> request it is handling
+    busy: HashMap<Tag, RequestId>,
 }
 
 #[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ModuleSupervisorMessage {
-    StartRequest(RequestId, Request),
+    StartRequest(ServiceId, RequestId, Request),
     CancelRequest(RequestId),
-    CompleteRequest(RequestId, Response)
+    CompleteRequest(RequestId, Response),
+    AttachService(ServiceId, Limits, Capabilities),
+    DetachService(ServiceId),
+    /// sent by the worker's panic hook, see frenezulo-macros
+    Panicked(RequestId),
+    /// sent by the worker's allocator, see `frenezulo::ReportingAllocator`
+    OutOfMemory(RequestId),
+    /// the request's timeout elapsed
+    TimeoutRequest(RequestId),
+    /// the request waited `queue_timeout_ms` without a worker becoming available
+    QueueTimeout(ServiceId, RequestId),
+    /// spawns idle workers after requests took some, so spawning does not delay the request
+    RefillPool(ServiceId),
+    ShrinkPools,
+}
+
+fn error_response(version: Version, status: u16, body: String) -> Response {
+    Response {
+        metadata: ResponseMetadata {
+            headers: MultiMap::new(),
+            status,
+            version
+        },
+        body: body.into_bytes()
+    }
+}
+
+/// Answer to requests shed because the service is at its concurrency limit
+fn overloaded_response(version: Version, limits: &Limits, body: &str) -> Response {
+    let mut response = error_response(version, 503, body.to_owned());
+    let retry_after = ((limits.queue_timeout_ms + 999) / 1000).max(1);
+    response.metadata.headers.insert("retry-after".to_owned(), serde_bytes::ByteBuf::from(retry_after.to_string().into_bytes()));
+    response
+}
+
+fn spawn_worker(module: &WasmModule, config: &ProcessConfig) -> Result<Worker, LunaticError> {
+    let tag = Tag::new();
+    let process = module.spawn_link_config::<WorkerMessage, WorkerSerializer>(wasm::ENTRY_POINT, &[], config, tag)?;
+    Ok(Worker { tag, process, served: 0, spawned_at: Instant::now() })
 }
 
 impl ModuleSupervisor {
-    fn respond(&self, request_id: RequestId, response: Response) {
-        self.supervisor.send(ServiceRegistryMessage::CompleteRequest(request_id, self.service_id, response));
+    fn respond(&self, service_id: ServiceId, request_id: RequestId, response: Response) {
+        self.supervisor.send(ServiceRegistryMessage::CompleteRequest(request_id, service_id, response));
     }
 
-    pub fn start_request(&mut self, request_id: RequestId, request: Request) {
-        static MODULE_TIMEOUT : Duration = Duration::from_millis(30);
-        let timeout_response : Response = Response {
-            metadata: ResponseMetadata {
-                headers: MultiMap::new(),
-                status: 504,
-                version: Version::Http11
-            },
-            body: "Service timed out".to_owned().into_bytes()
+    pub fn attach_service(&mut self, service_id: ServiceId, limits: Limits, capabilities: Capabilities) {
+        let mut config = ProcessConfig::new().expect("needs to create configs");
+        config.set_max_memory(limits.max_memory);
+        // nothing is granted unless the service's policy says so, see Capabilities::check
+        config.set_can_spawn_processes(capabilities.spawn);
+        config.set_can_compile_modules(capabilities.compile);
+        config.set_can_create_configs(false);
+        for dir in capabilities.dirs() {
+            config.preopen_dir(dir);
+        }
+        if let Some(max_fuel) = limits.max_fuel {
+            config.set_max_fuel(max_fuel);
+        }
+        self.services.insert(service_id, Pool {
+            limits,
+            config,
+            idle: vec![],
+            active: 0,
+            queue: VecDeque::new(),
+            target: limits.pool_min,
+            missed: false
+        });
+        self.supervisor.send(ServiceRegistryMessage::ServiceReady(service_id));
+        self.refill_pool(service_id);
+    }
+
+    pub fn detach_service(&mut self, service_id: ServiceId) {
+        if let Some(pool) = self.services.remove(&service_id) {
+            pool.idle.iter().for_each(|worker| worker.process.kill());
+        }
+        let busy = &mut self.busy;
+        self.outstanding_requests.retain(|_, outstanding| {
+            if outstanding.service_id == service_id {
+                busy.remove(&outstanding.worker.tag);
+                outstanding.worker.process.kill();
+            }
+            outstanding.service_id != service_id
+        });
+    }
+
+    pub fn start_request(&mut self, service_id: ServiceId, request_id: RequestId, request: Request) {
+        let pool = match self.services.get_mut(&service_id) {
+            Some(pool) => pool,
+            None => {
+                println!("Request {request_id:?} for detached service {service_id:?}");
+                return;
+            }
         };
+        let limits = pool.limits;
 
-        let mut config = ProcessConfig::new().expect("needs to create configs");
-        config.set_max_memory(1024 * 1024 * 4); // 4kb
+        if pool.active >= limits.max_concurrency {
+            if pool.queue.len() >= limits.queue_size {
+                let response = overloaded_response(request.metadata.version, &limits, "Service overloaded");
+                self.respond(service_id, request_id, response);
+                return;
+            }
+            pool.queue.push_back((request_id, request));
+            Process::<ModuleSupervisorMessage, WorkerSerializer>::this()
+                .send_after(ModuleSupervisorMessage::QueueTimeout(service_id, request_id), Duration::from_millis(limits.queue_timeout_ms));
+            return;
+        }
+        self.dispatch(service_id, request_id, request);
+    }
+
+    /// Hands the request to an idle or new worker, the service has to be below its concurrency limit
+    fn dispatch(&mut self, service_id: ServiceId, request_id: RequestId, request: Request) {
+        let pool = match self.services.get_mut(&service_id) {
+            Some(pool) => pool,
+            None => return
+        };
+        let limits = pool.limits;
+        let version = request.metadata.version.clone();
+
+        // reused workers can outlive max_lifetime_ms while they are idle
+        let mut idle = None;
+        while let Some(worker) = pool.idle.pop() {
+            if !self.reuse || worker.spawned_at.elapsed() < Duration::from_millis(limits.max_lifetime_ms) {
+                idle = Some(worker);
+                break;
+            }
+            worker.process.kill();
+        }
+
+        let new_worker = match idle {
+            Some(worker) => Ok(worker),
+            None => {
+                // keep more workers ready while requests arrive faster than they are refilled
+                pool.missed = true;
+                pool.target = (pool.target + 1).min(limits.pool_max);
+                spawn_worker(&self.module, &pool.config)
+            }
+        };
+        let refill = pool.idle.len() < pool.target;
 
-        let new_worker : Result<Process<WorkerMessage, WorkerSerializer>, LunaticError> = self.module.spawn_link_config::<WorkerMessage, WorkerSerializer>(
-            "frenezulo_main", &[], &config, request_id.tag);
         match new_worker {
             Ok(worker) => {
-                self.outstanding_requests.insert(request_id, worker.clone());
-                worker.send(WorkerMessage::Request(request_id, request, Process::this()));
-                self.supervisor.send_after(ServiceRegistryMessage::CompleteRequest(request_id, self.service_id, timeout_response), MODULE_TIMEOUT);
+                pool.active += 1;
+                worker.process.send(WorkerMessage::Request(request_id, request, Process::this()));
+                self.busy.insert(worker.tag, request_id);
+                self.outstanding_requests.insert(request_id, Outstanding {
+                    service_id,
+                    worker,
+                    limits,
+                    version,
+                    panicked: false,
+                    out_of_memory: false
+                });
+                let this = Process::<ModuleSupervisorMessage, WorkerSerializer>::this();
+                this.send_after(ModuleSupervisorMessage::TimeoutRequest(request_id), Duration::from_millis(limits.timeout_ms));
+                if refill {
+                    this.send(ModuleSupervisorMessage::RefillPool(service_id));
+                }
             },
             Err(err) => {
                 println!("Failed to start worker {err:?}");
                 let response = submillisecond::http::Response::builder()
                     .status(503)
-                    .version(request.metadata.version.into())
+                    .version(version.into())
                     .body(b"503 - Failed to start service".to_vec()).expect("Build 503 has to be possible");
-                self.respond(request_id, response.into());
+                self.respond(service_id, request_id, response.into());
+            }
+        }
+    }
+
+    pub fn refill_pool(&mut self, service_id: ServiceId) {
+        let pool = match self.services.get_mut(&service_id) {
+            Some(pool) => pool,
+            None => return
+        };
+        while pool.idle.len() < pool.target {
+            match spawn_worker(&self.module, &pool.config) {
+                Ok(worker) => pool.idle.push(worker),
+                Err(err) => {
+                    println!("Failed to start idle worker {err:?}");
+                    break;
+                }
             }
         }
     }
 
+    /// Pools that had enough idle workers since the last call keep one worker less
+    pub fn shrink_pools(&mut self) {
+        for pool in self.services.values_mut() {
+            if !pool.missed {
+                pool.target = pool.target.saturating_sub(1).max(pool.limits.pool_min);
+            }
+            pool.missed = false;
+            if pool.idle.len() > pool.target {
+                pool.idle.split_off(pool.target).iter().for_each(|worker| worker.process.kill());
+            }
+        }
+    }
+
+    /// Starts the oldest queued requests of the service, as far as its concurrency limit allows
+    fn start_queued(&mut self, service_id: ServiceId) {
+        loop {
+            let next = match self.services.get_mut(&service_id) {
+                Some(pool) if pool.active < pool.limits.max_concurrency => pool.queue.pop_front(),
+                _ => None
+            };
+            match next {
+                Some((request_id, request)) => self.dispatch(service_id, request_id, request),
+                None => return
+            }
+        }
+    }
+
+    pub fn queue_timeout(&mut self, service_id: ServiceId, request_id: RequestId) {
+        let pool = match self.services.get_mut(&service_id) {
+            Some(pool) => pool,
+            None => return
+        };
+        let index = match pool.queue.iter().position(|(queued_id, _)| *queued_id == request_id) {
+            Some(index) => index,
+            // started in the meantime
+            None => return
+        };
+        if let Some((_, request)) = pool.queue.remove(index) {
+            let response = overloaded_response(request.metadata.version, &pool.limits, "Service queue timed out");
+            self.respond(service_id, request_id, response);
+        }
+    }
+
+    /// Forgets the request, the caller has to call `start_queued` once the worker is dealt with
+    fn finish(&mut self, request_id: RequestId) -> Option<Outstanding> {
+        let outstanding = self.outstanding_requests.remove(&request_id)?;
+        self.busy.remove(&outstanding.worker.tag);
+        if let Some(pool) = self.services.get_mut(&outstanding.service_id) {
+            pool.active -= 1;
+        }
+        Some(outstanding)
+    }
+
     pub fn cancel_request(&mut self, request_id: RequestId) {
-        match self.outstanding_requests.remove(&request_id) {
-            Some(worker) => {
-                worker.kill();
+        match self.finish(request_id) {
+            Some(Outstanding { service_id, worker, .. }) => {
+                worker.process.kill();
+                self.start_queued(service_id);
             }
             None => ()
         }
     }
 
     pub fn complete_request(&mut self, request_id: RequestId, response: Response) {
-        match self.outstanding_requests.remove(&request_id) {
-            Some(worker) => {
-                self.respond(request_id, response);
-                worker.kill();
+        match self.finish(request_id) {
+            Some(Outstanding { service_id, mut worker, limits, .. }) => {
+                self.respond(service_id, request_id, response);
+                worker.served += 1;
+                // reused workers go back to the pool, which is trimmed to its target by shrink_pools
+                match self.services.get_mut(&service_id) {
+                    Some(pool) if self.reuse
+                        && worker.served < limits.max_requests
+                        && worker.spawned_at.elapsed() < Duration::from_millis(limits.max_lifetime_ms) =>
+                        pool.idle.push(worker),
+                    _ => worker.process.kill()
+                }
+                self.start_queued(service_id);
+            }
+            None => ()
+        }
+    }
+
+    pub fn panicked(&mut self, request_id: RequestId) {
+        if let Some(outstanding) = self.outstanding_requests.get_mut(&request_id) {
+            outstanding.panicked = true;
+        }
+    }
+
+    pub fn out_of_memory(&mut self, request_id: RequestId) {
+        if let Some(outstanding) = self.outstanding_requests.get_mut(&request_id) {
+            outstanding.out_of_memory = true;
+        }
+    }
+
+    /// Kills the worker if it is still running, completed requests are ignored
+    pub fn timeout_request(&mut self, request_id: RequestId) {
+        match self.finish(request_id) {
+            Some(Outstanding { service_id, worker, limits, version, .. }) => {
+                worker.process.kill();
+                let response = error_response(version, 504, format!("Service timed out after {}ms", limits.timeout_ms));
+                self.respond(service_id, request_id, response);
+                self.start_queued(service_id);
+            }
+            None => ()
+        }
+    }
+
+    /// Workers die without answering if they panic, or trap otherwise. Running out of memory or fuel is a trap
+    /// without a panic, as allocation failures abort. Which of the two it was is not reported by lunatic.
+    pub fn worker_died(&mut self, tag: Tag) {
+        let request_id = match self.busy.get(&tag) {
+            Some(request_id) => *request_id,
+            None => {
+                // idle workers are replaced by the next refill
+                self.services.values_mut().for_each(|pool| pool.idle.retain(|worker| worker.tag != tag));
+                return;
             }
+        };
+        match self.finish(request_id) {
+            Some(Outstanding { service_id, version, panicked: true, .. }) => {
+                self.respond(service_id, request_id, error_response(version, 500, "Service crashed".to_owned()));
+                self.start_queued(service_id);
+            },
+            Some(Outstanding { service_id, limits, version, out_of_memory: true, .. }) => {
+                let body = format!("Service exceeded its memory limit of {} bytes", limits.max_memory);
+                self.respond(service_id, request_id, error_response(version, 507, body));
+                self.start_queued(service_id);
+            },
+            Some(Outstanding { service_id, version, .. }) => {
+                // lunatic does not tell why a process trapped, running out of fuel looks like any other trap
+                self.respond(service_id, request_id, error_response(version, 500, "Service worker trapped".to_owned()));
+                self.start_queued(service_id);
+            },
             None => ()
         }
     }
 }
 
-pub fn start(tag: Tag, service_id: ServiceId, module_data: lunatic_envelop::Envelop, supervisor: Process<ServiceRegistryMessage>) -> Process<ModuleSupervisorMessage, WorkerSerializer> {
+/// Services are attached with `ModuleSupervisorMessage::AttachService`, they are reported ready once the module compiled
+pub fn start(tag: Tag, module_hash: String, module_data: lunatic_envelop::Envelop, allowed: &Capabilities, supervisor: Process<ServiceRegistryMessage>) -> Process<ModuleSupervisorMessage, WorkerSerializer> {
     println!("starting module supervisor");
     let mut config = ProcessConfig::new().expect("Needs to be able to create configs");
     config.set_can_spawn_processes(true);
     config.set_can_create_configs(true);
     config.set_can_compile_modules(true);
+    // workers can only be given directories their supervisor has
+    for dir in allowed.dirs() {
+        config.preopen_dir(dir);
+    }
 
     println!("spawning module supervisor");
-    Process::spawn_link_config_tag(&config, (service_id, module_data, supervisor), tag,
-    |(service_id, module_data, supervisor), mailbox: Mailbox<ModuleSupervisorMessage, WorkerSerializer>| 
+    Process::spawn_link_config_tag(&config, (module_hash, module_data, supervisor), tag,
+    |(module_hash, module_data, supervisor), mailbox: Mailbox<ModuleSupervisorMessage, WorkerSerializer>| 
     {
         let me = mailbox.this();
         let mailbox = mailbox.catch_link_failure();
         println!("compiling module {me:?}");
         let data = lunatic_envelop::open_envelop(module_data);
-        let module = WasmModule::new(&data);
-        if let Err(e) = module {
-            println!("Failed to compile {e:?}");
-            panic!("Failed to compile {e:?}");
-        }
+        let module = match WasmModule::new(&data) {
+            Ok(module) => module,
+            Err(e) => {
+                println!("Failed to compile {e:?}");
+                supervisor.send(ServiceRegistryMessage::ModuleFailed(module_hash, format!("Failed to compile {e:?}")));
+                return;
+            }
+        };
         println!("done compiling");
         
+        let reuse = wasm::manifest(&data).map_or(false, |manifest| manifest.reuse);
         let mut instance = ModuleSupervisor {
-            service_id,
             supervisor,
-            module: module.unwrap(),
-            outstanding_requests: HashMap::new()
+            module,
+            reuse,
+            services: HashMap::new(),
+            outstanding_requests: HashMap::new(),
+            busy: HashMap::new()
         };
+        me.send_after(ModuleSupervisorMessage::ShrinkPools, POOL_SHRINK_INTERVAL);
 
         loop {
             match mailbox.try_receive(Duration::MAX) {
                 lunatic::MailboxResult::Message(msg) =>
                     match msg {
-                        ModuleSupervisorMessage::StartRequest(request_id, request) =>
-                            instance.start_request(request_id, request),
+                        ModuleSupervisorMessage::StartRequest(service_id, request_id, request) =>
+                            instance.start_request(service_id, request_id, request),
                         ModuleSupervisorMessage::CancelRequest(request_id) =>
                             instance.cancel_request(request_id),
                         ModuleSupervisorMessage::CompleteRequest(request_id, response) =>
                             instance.complete_request(request_id, response),
+                        ModuleSupervisorMessage::AttachService(service_id, limits, capabilities) =>
+                            instance.attach_service(service_id, limits, capabilities),
+                        ModuleSupervisorMessage::DetachService(service_id) =>
+                            instance.detach_service(service_id),
+                        ModuleSupervisorMessage::Panicked(request_id) =>
+                            instance.panicked(request_id),
+                        ModuleSupervisorMessage::OutOfMemory(request_id) =>
+                            instance.out_of_memory(request_id),
+                        ModuleSupervisorMessage::TimeoutRequest(request_id) =>
+                            instance.timeout_request(request_id),
+                        ModuleSupervisorMessage::QueueTimeout(service_id, request_id) =>
+                            instance.queue_timeout(service_id, request_id),
+                        ModuleSupervisorMessage::RefillPool(service_id) =>
+                            instance.refill_pool(service_id),
+                        ModuleSupervisorMessage::ShrinkPools => {
+                            instance.shrink_pools();
+                            me.send_after(ModuleSupervisorMessage::ShrinkPools, POOL_SHRINK_INTERVAL);
+                        },
                     },
                 lunatic::MailboxResult::DeserializationFailed(err) => {println!("Deserialization Failed {err:?}"); panic!("Deserialization Failed {err:?}");},
                 lunatic::MailboxResult::TimedOut => todo!(),
-                lunatic::MailboxResult::LinkDied(tag) => {
-                    let request_id = RequestId { tag };
-                    match instance.outstanding_requests.remove(&request_id) {
-                        Some(_worker) => {
-                            instance.supervisor.send(ServiceRegistryMessage::CancelRequest(request_id, service_id));
-                        },
-                        None => ()
-                    }
-                },
+                lunatic::MailboxResult::LinkDied(tag) =>
+                    instance.worker_died(tag),
             }
         }
     })
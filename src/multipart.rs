@@ -0,0 +1,118 @@
+use anyhow::anyhow;
+
+pub struct Part<'a> {
+    pub name: Option<String>,
+    pub content_type: Option<String>,
+    pub body: &'a [u8],
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+pub fn boundary(content_type: &str) -> Option<&str> {
+    let (mime, params) = content_type.split_once(';')?;
+    if !mime.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+
+    params.split(';')
+        .filter_map(|param| param.split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim().trim_matches('"'))
+}
+
+fn parse_headers(headers: &str) -> (Option<String>, Option<String>) {
+    let mut name = None;
+    let mut content_type = None;
+    for line in headers.split("\r\n") {
+        match line.split_once(':') {
+            Some((key, value)) if key.trim().eq_ignore_ascii_case("content-disposition") => {
+                name = value.split(';')
+                    .filter_map(|param| param.split_once('='))
+                    .find(|(key, _)| key.trim() == "name")
+                    .map(|(_, value)| value.trim().trim_matches('"').to_owned());
+            },
+            Some((key, value)) if key.trim().eq_ignore_ascii_case("content-type") => {
+                content_type = Some(value.trim().to_owned());
+            },
+            _ => ()
+        }
+    }
+    (name, content_type)
+}
+
+pub fn parse<'a>(body: &'a [u8], boundary: &str) -> anyhow::Result<Vec<Part<'a>>> {
+    let delimiter = format!("--{boundary}");
+    let part_delimiter = format!("\r\n--{boundary}");
+
+    let start = find(body, delimiter.as_bytes()).ok_or_else(|| anyhow!("multipart body has no boundary"))?;
+    let mut rest = &body[start + delimiter.len()..];
+    let mut parts = Vec::new();
+
+    loop {
+        if rest.starts_with(b"--") {
+            return Ok(parts);
+        }
+        rest = rest.strip_prefix(b"\r\n").ok_or_else(|| anyhow!("malformed multipart boundary"))?;
+
+        let headers_end = find(rest, b"\r\n\r\n").ok_or_else(|| anyhow!("multipart part has no header terminator"))?;
+        let (name, content_type) = parse_headers(std::str::from_utf8(&rest[..headers_end])?);
+        rest = &rest[headers_end + 4..];
+
+        let body_end = find(rest, part_delimiter.as_bytes()).ok_or_else(|| anyhow!("multipart part is not terminated"))?;
+        parts.push(Part { name, content_type, body: &rest[..body_end] });
+        rest = &rest[body_end + part_delimiter.len()..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boundary_of_form_data() {
+        assert_eq!(boundary("multipart/form-data; boundary=abc"), Some("abc"));
+        assert_eq!(boundary("Multipart/Form-Data; charset=utf-8; Boundary=\"a b\""), Some("a b"));
+        assert_eq!(boundary("multipart/mixed; boundary=abc"), None);
+        assert_eq!(boundary("multipart/form-data"), None);
+    }
+
+    #[test]
+    fn parses_parts() {
+        let body = b"preamble\r\n--xyz\r\n\
+            Content-Disposition: form-data; name=\"metadata\"\r\n\r\n\
+            {\"prefix\": \"hello\"}\r\n--xyz\r\n\
+            Content-Disposition: form-data; name=\"module\"; filename=\"hello.wasm\"\r\n\
+            Content-Type: application/wasm\r\n\r\n\
+            \0asm\r\n\x01\r\n--xyz--\r\n";
+        let parts = parse(body, "xyz").unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].name.as_deref(), Some("metadata"));
+        assert_eq!(parts[0].content_type, None);
+        assert_eq!(parts[0].body, b"{\"prefix\": \"hello\"}");
+        assert_eq!(parts[1].name.as_deref(), Some("module"));
+        assert_eq!(parts[1].content_type.as_deref(), Some("application/wasm"));
+        // line breaks inside the body are kept, only the one before the delimiter is not part of it
+        assert_eq!(parts[1].body, b"\0asm\r\n\x01");
+    }
+
+    #[test]
+    fn rejects_truncated_bodies() {
+        let body = b"--xyz\r\nContent-Disposition: form-data; name=\"module\"\r\n\r\n\0asm\r\n--xyz--\r\n";
+        for len in 0..body.len() - "--xyz--\r\n".len() {
+            assert!(parse(&body[..len], "xyz").is_err(), "accepted body truncated to {len} bytes");
+        }
+        assert_eq!(parse(body, "xyz").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn rejects_a_missing_header_terminator() {
+        assert!(parse(b"--xyz\r\nContent-Disposition: form-data; name=\"module\"\r\n--xyz--", "xyz").is_err());
+    }
+
+    #[test]
+    fn rejects_a_boundary_without_line_break() {
+        assert!(parse(b"--xyzContent-Disposition: form-data\r\n\r\nbody\r\n--xyz--", "xyz").is_err());
+    }
+}
@@ -0,0 +1,94 @@
+use std::{cell::RefCell, collections::hash_map::RandomState, hash::{BuildHasher, Hasher}, time::{Duration, Instant}};
+
+use frenezulo::RequestId;
+
+use crate::router::{self, RoutingSnapshot, RouteTarget};
+
+/// request ids fetched from the router at once, starting small as most connections only make a few requests,
+/// and doubling with every batch a connection uses up
+static MIN_REQUEST_ID_BATCH : usize = 4;
+static MAX_REQUEST_ID_BATCH : usize = 256;
+
+/// Routing table of the current (connection) process, so requests are routed without asking the router.
+/// The snapshot is refreshed once it is older than the maximum age, until then changes are seen with a delay,
+/// including routes added for paths that did not match before. That is safe as the service registry answers
+/// requests to deleted services, and forwards requests to replaced services.
+struct RouteCache {
+    snapshot: Option<RoutingSnapshot>,
+    fetched_at: Instant,
+    request_ids: Vec<RequestId>,
+    request_id_batch: usize,
+    rng: u64,
+}
+
+thread_local! {
+    static CACHE: RefCell<RouteCache> = RefCell::new(RouteCache {
+        snapshot: None,
+        fetched_at: Instant::now(),
+        request_ids: vec![],
+        request_id_batch: MIN_REQUEST_ID_BATCH,
+        // xorshift must not be seeded with 0
+        rng: RandomState::new().build_hasher().finish() | 1,
+    });
+}
+
+impl RouteCache {
+    /// Fetches the snapshot if it changed, and the next batch of request ids once there are none left,
+    /// so the first request of a connection gets both with one call to the router
+    fn refresh(&mut self) {
+        let request_ids = if self.request_ids.is_empty() {
+            let batch = self.request_id_batch;
+            self.request_id_batch = (batch * 2).min(MAX_REQUEST_ID_BATCH);
+            batch
+        }
+        else {
+            0
+        };
+        let known_version = self.snapshot.as_ref().map(|snapshot| snapshot.version);
+        let (snapshot, request_ids) = router::refresh(known_version, request_ids);
+        if snapshot.is_some() {
+            self.snapshot = snapshot;
+        }
+        self.request_ids.extend(request_ids);
+        self.fetched_at = Instant::now();
+    }
+
+    fn next_request_id(&mut self) -> RequestId {
+        if self.request_ids.is_empty() {
+            self.refresh();
+        }
+        self.request_ids.pop().expect("router has to return the requested ids")
+    }
+
+    fn next_random(&mut self) -> u64 {
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    fn route(&mut self, host: Option<&str>, path: &str, pinned: Option<&str>, max_age: Duration) -> Option<RouteTarget> {
+        if self.snapshot.is_none() || self.fetched_at.elapsed() > max_age {
+            self.refresh();
+        }
+
+        let random = self.next_random();
+        let (route, backend, split) = self.snapshot.as_ref()?.pick(host, path, pinned, random)
+            .map(|(route, backend, split)| (route, backend.clone(), split))?;
+        Some(RouteTarget {
+            route,
+            service_id: backend.service_id,
+            request_id: self.next_request_id(),
+            version: backend.version,
+            split,
+            methods: backend.methods,
+            path: backend.path
+        })
+    }
+}
+
+/// Routes the request to the service with the longest matching prefix, of the `host` or otherwise of the path routes
+pub fn route(host: Option<&str>, path: &str, pinned: Option<&str>, max_age: Duration) -> Option<RouteTarget> {
+    CACHE.with(|cache| cache.borrow_mut().route(host, path, pinned, max_age))
+}
@@ -1,18 +1,300 @@
 use std::collections::HashMap;
 
 use lunatic::{process::ProcessRef, Tag, abstract_process};
+use serde::{Serialize, Deserialize};
 
-use crate::{service_registry};
-use frenezulo::{ ServiceId, RequestId};
+use ed25519_dalek::PublicKey;
 
-pub struct Router(HashMap<String, ServiceId>);
+use crate::{service_registry::{self, ModuleInfo}, catalog::{Catalog, CatalogEntry}, config::{Config, Limits, LimitOverrides, Capabilities}, signing, wasm, routes::{self, Route, RouteTable, PathRewrite}};
+use frenezulo::{ ServiceId, RequestId, Manifest};
+
+pub static DEFAULT_VERSION : &str = "default";
+pub static DEFAULT_WEIGHT : u32 = 100;
+/// path of the management endpoints, unless they are served on `server.admin_bind`
+pub static ADMIN_PREFIX : &str = "services";
+
+/// Checks a route can be registered, `admin_reserved` if the management endpoints share the listener with the services
+pub fn check_route(route: &Route, admin_reserved: bool) -> Result<(), String> {
+    let prefix = &route.prefix;
+    // an empty prefix without a host is the fallback, serving every request no other route matches
+    if let Some(host) = &route.host {
+        routes::check_host(host)?;
+    }
+    if prefix.split('/').any(|segment| segment == "." || segment == "..") {
+        return Err(format!("Prefix {prefix:?} can't contain \".\" or \"..\" segments"));
+    }
+    // the management endpoints are matched before any route, regardless of the host
+    if admin_reserved && prefix.split('/').next() == Some(ADMIN_PREFIX) {
+        return Err(format!("Prefix {prefix:?} conflicts with the reserved path /{ADMIN_PREFIX}"));
+    }
+    Ok(())
+}
+
+/// Settings of a service given when registering it
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServiceOptions {
+    pub version: Option<String>,
+    pub weight: Option<u32>,
+    pub limits: LimitOverrides,
+    pub path: PathRewrite,
+    pub capabilities: Capabilities,
+}
+
+impl ServiceOptions {
+    pub fn check(&self) -> Result<(), String> {
+        if self.limits.timeout_ms == Some(0) || self.limits.max_memory == Some(0) {
+            return Err("Limits have to be above 0".to_owned());
+        }
+        self.path.check()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Backend {
+    pub service_id: ServiceId,
+    pub version: String,
+    pub weight: u32,
+    /// SHA-256 of the module
+    pub module: String,
+    /// limits set by the operator, the manifest and defaults apply to everything else
+    pub limits: LimitOverrides,
+    pub path: PathRewrite,
+    pub capabilities: Capabilities,
+    pub manifest: Manifest,
+    /// registered from the configuration file, which is read again on restart instead of the catalog
+    pub configured: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RouteTarget {
+    /// the matched route, its prefix is the longest registered prefix of the request path
+    pub route: Route,
+    pub service_id: ServiceId,
+    pub request_id: RequestId,
+    pub version: String,
+    /// whether the route is split between multiple versions
+    pub split: bool,
+    /// methods the service accepts, all if empty
+    pub methods: Vec<String>,
+    pub path: PathRewrite,
+}
+
+/// The part of a backend the listeners need to route to it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoutingBackend {
+    pub service_id: ServiceId,
+    pub version: String,
+    pub weight: u32,
+    /// methods the service accepts, all if empty
+    pub methods: Vec<String>,
+    pub path: PathRewrite,
+}
+
+impl From<&Backend> for RoutingBackend {
+    fn from(backend: &Backend) -> Self {
+        Self {
+            service_id: backend.service_id,
+            version: backend.version.clone(),
+            weight: backend.weight,
+            methods: backend.manifest.methods.clone(),
+            path: backend.path.clone()
+        }
+    }
+}
+
+/// The routing table as published to the listeners, see route_cache.rs
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct RoutingSnapshot {
+    /// increases with every change of the routes
+    pub version: u64,
+    routes: RouteTable<Vec<RoutingBackend>>,
+}
+
+impl RoutingSnapshot {
+    fn new(version: u64, routes: &RouteTable<Vec<Backend>>) -> Self {
+        let mut snapshot = Self { version, routes: RouteTable::default() };
+        for (route, backends) in routes.entries() {
+            snapshot.routes.insert(&route, backends.iter().map(RoutingBackend::from).collect());
+        }
+        snapshot
+    }
+
+    /// Picks a backend of the route matching `host` and `path`, returns the route, the backend and whether the route is split
+    pub fn pick(&self, host: Option<&str>, path: &str, pinned: Option<&str>, random: u64) -> Option<(Route, &RoutingBackend, bool)> {
+        let (route, backends) = self.routes.route(host, path)?;
+        let split = backends.len() > 1;
+
+        if let Some(backend) = pinned.and_then(|pinned| backends.iter().find(|b| b.version == pinned)) {
+            return Some((route, backend, split));
+        }
+
+        let total : u64 = backends.iter().map(|b| b.weight as u64).sum();
+        if total == 0 {
+            return backends.first().map(|b| (route, b, split));
+        }
+
+        let mut point = random % total;
+        for backend in backends {
+            if point < backend.weight as u64 {
+                return Some((route, backend, split));
+            }
+            point -= backend.weight as u64;
+        }
+        None
+    }
+}
+
+/// A backend that is compiling and gets added to `route` once ready
+struct Staged {
+    route: Route,
+    backend: Backend,
+}
+
+pub struct Router {
+    routes: RouteTable<Vec<Backend>>,
+    staged: HashMap<ServiceId, Staged>,
+    version: u64,
+    /// what is sent to the listeners, rebuilt whenever the routes change
+    snapshot: RoutingSnapshot,
+    catalog: Catalog,
+    defaults: Limits,
+    /// the most any service may be granted
+    allowed: Capabilities,
+    admin_reserved: bool,
+}
+
+impl Router {
+    fn register(&mut self, route: Route, options: ServiceOptions, data: Vec<u8>, configured: bool) -> ServiceId {
+        let id = ServiceId { tag: Tag::new() };
+        let ServiceOptions { version, weight, limits, path, capabilities } = options;
+        let version = version.unwrap_or_else(|| DEFAULT_VERSION.to_owned());
+        let module = ModuleInfo::new(&data);
+        let manifest = wasm::manifest(&data).unwrap_or_else(|e| {
+            println!("Ignoring manifest of {route} {e:?}");
+            Manifest::default()
+        });
+        let resolved_limits = limits.or(LimitOverrides::from(&manifest)).resolve(&self.defaults);
+        if let Err(e) = self.catalog.store_module(&module.hash, &data) {
+            println!("Failed to store module {:?} in catalog {e:?}", module.hash);
+        }
+
+        match self.routes.get(&route) {
+            Some(backends) => {
+                // the backend is only added once the new module compiled, see activate_service
+                let weight = weight
+                    .or_else(|| backends.iter().find(|b| b.version == version).map(|b| b.weight))
+                    .unwrap_or(DEFAULT_WEIGHT);
+                println!("Staging service {route} version {version:?} {id:?}");
+                self.staged.insert(id, Staged {
+                    route,
+                    backend: Backend { service_id: id, version, weight, module: module.hash.clone(), limits, path, capabilities: capabilities.clone(), manifest, configured }
+                });
+                service_registry::stage_service(id, module, resolved_limits, capabilities, data);
+            },
+            None => {
+                println!("Registered service {route} version {version:?} {id:?}");
+                self.routes.insert(&route, vec![Backend { service_id: id, version, weight: weight.unwrap_or(DEFAULT_WEIGHT), module: module.hash.clone(), limits, path, capabilities: capabilities.clone(), manifest, configured }]);
+                service_registry::add_service(id, module, resolved_limits, capabilities, data);
+            }
+        }
+        id
+    }
+
+    /// Publishes a new version of the routes and writes the catalog.
+    /// Staged backends are included in the catalog, so an interrupted replacement is retried on restart.
+    /// Services from the configuration file are left out, so removing them from the file unregisters them
+    fn persist(&mut self) {
+        self.version += 1;
+        self.snapshot = RoutingSnapshot::new(self.version, &self.routes);
+
+        let mut entries = self.routes.entries().into_iter()
+            .flat_map(|(route, backends)| backends.iter().map(move |b| (route.clone(), b)))
+            .filter(|(route, b)| !b.configured && !self.staged.values().any(|staged| &staged.route == route && staged.backend.version == b.version))
+            .map(|(route, b)| CatalogEntry {
+                host: route.host,
+                prefix: route.prefix,
+                version: b.version.clone(),
+                weight: b.weight,
+                module: b.module.clone(),
+                limits: b.limits,
+                path: b.path.clone(),
+                capabilities: b.capabilities.clone()
+            })
+            .collect::<Vec<_>>();
+        entries.extend(self.staged.values().filter(|staged| !staged.backend.configured).map(|staged| CatalogEntry {
+            host: staged.route.host.clone(),
+            prefix: staged.route.prefix.clone(),
+            version: staged.backend.version.clone(),
+            weight: staged.backend.weight,
+            module: staged.backend.module.clone(),
+            limits: staged.backend.limits,
+            path: staged.backend.path.clone(),
+            capabilities: staged.backend.capabilities.clone()
+        }));
+
+        if let Err(e) = self.catalog.save(&entries) {
+            println!("Failed to save catalog {e:?}");
+        }
+    }
+}
 
 #[abstract_process]
 impl Router {
     #[init]
-    fn init(_: ProcessRef<Self>, _: ()) -> Self {
-        Self(HashMap::new())
-    } 
+    fn init(_: ProcessRef<Self>, config: Config) -> Self {
+        let mut router = Self {
+            routes: RouteTable::default(),
+            staged: HashMap::new(),
+            version: 0,
+            snapshot: RoutingSnapshot::default(),
+            catalog: Catalog::new(&config.server.catalog),
+            defaults: config.defaults,
+            allowed: config.capabilities.clone(),
+            admin_reserved: config.server.admin_bind.is_none()
+        };
+
+        match router.catalog.load() {
+            Ok(entries) => {
+                for entry in entries {
+                    match router.catalog.load_module(&entry.module) {
+                        // the allowed capabilities may have been reduced since the service was added
+                        Ok(_) if entry.capabilities.check(&router.allowed).is_err() =>
+                            println!("Skipping {:?} from catalog, its capabilities are no longer allowed", entry.prefix),
+                        Ok(data) => {
+                            let route = Route::new(entry.host.as_deref(), &entry.prefix);
+                            let options = ServiceOptions { version: Some(entry.version), weight: Some(entry.weight), limits: entry.limits, path: entry.path, capabilities: entry.capabilities };
+                            router.register(route, options, data, false);
+                        },
+                        Err(e) => println!("Failed to load module {:?} of {:?} from catalog {e:?}", entry.module, entry.prefix)
+                    }
+                }
+            },
+            Err(e) => println!("Failed to load catalog {e:?}")
+        }
+
+        // configured services take precedence over the catalog, unless they are registered unchanged already
+        for service in config.services {
+            let data = match std::fs::read(&service.module) {
+                Ok(data) => data,
+                Err(e) => {
+                    println!("Failed to read module {:?} of {:?} {e:?}", service.module, service.prefix);
+                    continue;
+                }
+            };
+            let route = Route::new(service.host.as_deref(), &service.prefix);
+            let version = service.version.unwrap_or_else(|| DEFAULT_VERSION.to_owned());
+            let hash = ModuleInfo::new(&data).hash;
+            let unchanged = router.routes.get(&route)
+                .and_then(|backends| backends.iter().find(|b| b.version == version))
+                .map_or(false, |b| b.module == hash && b.limits == service.limits && b.path == service.path && b.capabilities == service.capabilities && service.weight.map_or(true, |w| w == b.weight));
+            if !unchanged {
+                let options = ServiceOptions { version: Some(version), weight: service.weight, limits: service.limits, path: service.path, capabilities: service.capabilities };
+                router.register(route, options, data, true);
+            }
+        }
+        router.persist();
+        router
+    }
 
     #[terminate]
     fn terminate(self) {
@@ -24,29 +306,247 @@ impl Router {
     }
 
     #[handle_request]
-    fn add_service(&mut self, prefix: String, data: serde_bytes::ByteBuf) -> ServiceId {
-        let id = ServiceId { tag: Tag::new() };
-        self.0.insert(prefix.clone(), id);
-        service_registry::add_service(id, data.into_vec());
-        println!("Registered service {prefix:?} {id:?}");
-        id
+    fn add_service(&mut self, route: Route, options: ServiceOptions, data: serde_bytes::ByteBuf) -> Result<ServiceId, String> {
+        check_route(&route, self.admin_reserved)?;
+        options.check()?;
+        options.capabilities.check(&self.allowed)?;
+        let manifest = wasm::manifest(&data).unwrap_or_default();
+        options.capabilities.require(&manifest.capabilities)?;
+        options.limits.or(LimitOverrides::from(&manifest)).resolve(&self.defaults).check()?;
+        let id = self.register(route, options, data.into_vec(), false);
+        self.persist();
+        Ok(id)
     }
 
+    #[handle_message]
+    fn activate_service(&mut self, id: ServiceId) {
+        let Staged { route, backend } = match self.staged.remove(&id) {
+            Some(staged) => staged,
+            None => return
+        };
+
+        match self.routes.get_mut(&route) {
+            Some(backends) => match backends.iter_mut().find(|b| b.version == backend.version) {
+                Some(current) => {
+                    let old_id = current.service_id;
+                    *current = backend;
+                    service_registry::drain_service(old_id, id);
+                    println!("Swapped service {route} {old_id:?} to {id:?}");
+                },
+                None => {
+                    println!("Added version {:?} to service {route} {id:?}", backend.version);
+                    backends.push(backend);
+                }
+            },
+            None => {
+                // removed while compiling
+                service_registry::delete_service(id);
+            }
+        }
+        self.persist();
+    }
+
+    /// Forgets a service whose module failed to compile, whether it was staged or routed already
+    #[handle_message]
+    fn discard_service(&mut self, id: ServiceId) {
+        if self.staged.remove(&id).is_none() {
+            let route = match self.routes.entries().into_iter().find(|(_, backends)| backends.iter().any(|b| b.service_id == id)) {
+                Some((route, _)) => route,
+                None => return
+            };
+            if let Some(backends) = self.routes.get_mut(&route) {
+                backends.retain(|b| b.service_id != id);
+                if backends.is_empty() {
+                    self.routes.remove(&route);
+                }
+            }
+            println!("Removed failed service {route} {id:?}");
+        }
+        self.persist();
+    }
+
+    /// A snapshot of the routes unless `known_version` is current, and `request_ids` new request ids.
+    /// Request ids are tags, which are only unique within the process creating them.
     #[handle_request]
-    fn create_request(&self, prefix: String) -> Option<(ServiceId, RequestId)> {
-        let service_id = self.0.get(&prefix)?;
-        let request_id = RequestId {
-            tag: Tag::new()
+    fn refresh(&self, known_version: Option<u64>, request_ids: usize) -> (Option<RoutingSnapshot>, Vec<RequestId>) {
+        let snapshot = match known_version {
+            Some(version) if version == self.version => None,
+            _ => Some(self.snapshot.clone())
         };
-        Some((*service_id, request_id))
+        let request_ids = (0..request_ids).map(|_| RequestId { tag: Tag::new() }).collect();
+        (snapshot, request_ids)
+    }
+
+    #[handle_request]
+    fn remove_service(&mut self, route: Route, version: Option<String>) -> Vec<ServiceId> {
+        // replacements that are still compiling go as well, they would be activated and persisted otherwise
+        let staged = self.staged.iter()
+            .filter(|(_, staged)| staged.route == route && version.as_ref().map_or(true, |version| &staged.backend.version == version))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        let mut removed = match version {
+            None => self.routes.remove(&route).unwrap_or_default(),
+            Some(version) => match self.routes.get_mut(&route) {
+                Some(backends) => {
+                    let (removed, kept) : (Vec<Backend>, Vec<Backend>) = backends.drain(..).partition(|b| b.version == version);
+                    *backends = kept;
+                    if backends.is_empty() {
+                        self.routes.remove(&route);
+                    }
+                    removed
+                },
+                None => vec![]
+            }
+        };
+        removed.extend(staged.iter().filter_map(|id| self.staged.remove(id)).map(|staged| staged.backend));
+
+        let removed = removed.iter()
+            .map(|backend| {
+                service_registry::delete_service(backend.service_id);
+                println!("Removed service {route} version {:?} {:?}", backend.version, backend.service_id);
+                backend.service_id
+            })
+            .collect();
+        self.persist();
+        removed
+    }
+
+    #[handle_request]
+    fn set_weights(&mut self, route: Route, weights: HashMap<String, u32>) -> Result<(), String> {
+        let backends = self.routes.get_mut(&route).ok_or_else(|| format!("Unknown Service {route}"))?;
+        if let Some(version) = weights.keys().find(|version| !backends.iter().any(|b| &b.version == *version)) {
+            return Err(format!("Unknown version {version:?}"));
+        }
+
+        let total : u64 = backends.iter()
+            .map(|b| *weights.get(&b.version).unwrap_or(&b.weight) as u64)
+            .sum();
+        if total == 0 {
+            return Err("At least one version needs a weight above 0".to_owned());
+        }
+
+        for backend in backends.iter_mut() {
+            if let Some(weight) = weights.get(&backend.version) {
+                backend.weight = *weight;
+            }
+        }
+        self.persist();
+        Ok(())
+    }
+
+    /// Whether a routed or staged service uses the module with this SHA-256
+    #[handle_request]
+    fn has_module(&self, hash: String) -> bool {
+        self.routes.entries().into_iter().any(|(_, backends)| backends.iter().any(|b| b.module == hash))
+            || self.staged.values().any(|staged| staged.backend.module == hash)
+    }
+
+    #[handle_request]
+    fn list_services(&self) -> Vec<(Route, Backend)> {
+        self.routes.entries().into_iter()
+            .flat_map(|(route, backends)| backends.iter().map(move |b| (route.clone(), b.clone())))
+            .collect()
+    }
+
+    #[handle_request]
+    fn get_service(&self, route: Route) -> Vec<Backend> {
+        self.routes.get(&route).cloned().unwrap_or_default()
+    }
+}
+
+pub fn refresh(known_version: Option<u64>, request_ids: usize) -> (Option<RoutingSnapshot>, Vec<RequestId>) {
+    ProcessRef::<Router>::lookup("router").expect("router has to be found").refresh(known_version, request_ids)
+}
+
+/// Verifies the signature and validates the module in the calling process, so the router is not blocked by compiling it.
+/// A module that is loaded already is not compiled again. Unsigned or untrusted modules are rejected before they are parsed. Modules from the catalog and config are trusted.
+/// Without a prefix the one from the module's manifest is used, or the whole host if one is given.
+/// Returns the route the service was added under.
+pub fn add_service(trusted_keys: &[PublicKey], host: Option<String>, prefix: Option<String>, options: ServiceOptions, module_data: Vec<u8>, signature: Option<Vec<u8>>) -> Result<(Route, ServiceId), String> {
+    signing::verify(trusted_keys, &module_data, signature.as_deref())?;
+    let manifest = wasm::validate(&module_data).map_err(|e| e.to_string())?;
+    let prefix = prefix.or(manifest.prefix)
+        .or_else(|| host.as_ref().map(|_| String::new()))
+        .ok_or_else(|| "No prefix given and the module's manifest declares none".to_owned())?;
+    let route = Route::new(host.as_deref(), &prefix);
+    let router = ProcessRef::<Router>::lookup("router").expect("router has to be found");
+    if !router.has_module(ModuleInfo::new(&module_data).hash) {
+        wasm::compile(&module_data).map_err(|e| e.to_string())?;
     }
+    let id = router.add_service(route.clone(), options, serde_bytes::ByteBuf::from(module_data))?;
+    Ok((route, id))
+}
+
+pub fn activate_service(id: ServiceId) {
+    ProcessRef::<Router>::lookup("router").expect("router has to be found").activate_service(id)
 }
 
-pub fn create_request(prefix: String) -> Option<(ServiceId, RequestId)> {
-    ProcessRef::<Router>::lookup("router").expect("router has to be found").create_request(prefix)
+pub fn discard_service(id: ServiceId) {
+    ProcessRef::<Router>::lookup("router").expect("router has to be found").discard_service(id)
 }
 
-pub fn add_service(prefix: String, module_data: Vec<u8>) -> ServiceId {
-    ProcessRef::<Router>::lookup("router").expect("router has to be found")
-        .add_service(prefix, serde_bytes::ByteBuf::from(module_data))
-}
\ No newline at end of file
+pub fn remove_service(route: Route, version: Option<String>) -> Vec<ServiceId> {
+    ProcessRef::<Router>::lookup("router").expect("router has to be found").remove_service(route, version)
+}
+
+pub fn set_weights(route: Route, weights: HashMap<String, u32>) -> Result<(), String> {
+    ProcessRef::<Router>::lookup("router").expect("router has to be found").set_weights(route, weights)
+}
+
+pub fn list_services() -> Vec<(Route, Backend)> {
+    ProcessRef::<Router>::lookup("router").expect("router has to be found").list_services()
+}
+
+pub fn get_service(route: Route) -> Vec<Backend> {
+    ProcessRef::<Router>::lookup("router").expect("router has to be found").get_service(route)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backend(version: &str, weight: u32) -> RoutingBackend {
+        RoutingBackend { service_id: ServiceId { tag: Tag::new() }, version: version.to_owned(), weight, methods: vec![], path: PathRewrite::default() }
+    }
+
+    fn snapshot(backends: Vec<RoutingBackend>) -> RoutingSnapshot {
+        let mut routes = RouteTable::default();
+        routes.insert(&Route::new(None, "api"), backends);
+        RoutingSnapshot { version: 1, routes }
+    }
+
+    fn picked(snapshot: &RoutingSnapshot, pinned: Option<&str>, random: u64) -> Option<String> {
+        snapshot.pick(None, "/api/users", pinned, random).map(|(_, backend, _)| backend.version.clone())
+    }
+
+    #[test]
+    fn picks_by_weight() {
+        let snapshot = snapshot(vec![backend("v1", 3), backend("v2", 1)]);
+        let versions = (0..8).map(|random| picked(&snapshot, None, random).unwrap()).collect::<Vec<_>>();
+        assert_eq!(versions, ["v1", "v1", "v1", "v2", "v1", "v1", "v1", "v2"]);
+        assert_eq!(picked(&snapshot, None, u64::MAX).as_deref(), Some("v2"));
+    }
+
+    #[test]
+    fn skips_versions_without_weight() {
+        let weighted = snapshot(vec![backend("v1", 0), backend("v2", 5)]);
+        assert!((0..10).all(|random| picked(&weighted, None, random).as_deref() == Some("v2")));
+        // without any weight the first version gets everything
+        let unweighted = snapshot(vec![backend("v1", 0), backend("v2", 0)]);
+        assert_eq!(picked(&unweighted, None, 7).as_deref(), Some("v1"));
+    }
+
+    #[test]
+    fn pinned_versions_ignore_weights() {
+        let snapshot = snapshot(vec![backend("v1", 1), backend("v2", 0)]);
+        assert_eq!(picked(&snapshot, Some("v2"), 0).as_deref(), Some("v2"));
+        assert_eq!(picked(&snapshot, Some("v3"), 0).as_deref(), Some("v1"));
+    }
+
+    #[test]
+    fn reports_split_routes() {
+        let split = |snapshot: &RoutingSnapshot| snapshot.pick(None, "/api", None, 0).map(|(route, _, split)| (route, split));
+        assert_eq!(split(&snapshot(vec![backend("v1", 1)])), Some((Route::new(None, "api"), false)));
+        assert_eq!(split(&snapshot(vec![backend("v1", 1), backend("v2", 0)])), Some((Route::new(None, "api"), true)));
+        assert_eq!(snapshot(vec![backend("v1", 1)]).pick(None, "/other", None, 0).map(|(route, _, _)| route), None);
+    }
+}
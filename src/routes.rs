@@ -0,0 +1,298 @@
+use std::{collections::HashMap, fmt};
+
+use serde::{Serialize, Deserialize};
+
+use crate::trie::{self, PrefixTrie};
+
+/// Where a service is served, a path prefix optionally bound to a host.
+/// Hosts can have a wildcard subdomain like `*.example.com`, which matches all subdomains of `example.com`.
+/// The empty prefix without a host is the fallback route, matching every path.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Route {
+    pub host: Option<String>,
+    pub prefix: String,
+}
+
+impl Route {
+    pub fn new(host: Option<&str>, prefix: &str) -> Self {
+        Self {
+            host: host.map(canonical_host).filter(|host| !host.is_empty()),
+            prefix: trie::canonical(prefix),
+        }
+    }
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.host {
+            Some(host) => write!(f, "{host}/{}", self.prefix),
+            None => write!(f, "/{}", self.prefix),
+        }
+    }
+}
+
+/// Hosts compare case insensitive, without a port and trailing dot
+pub fn canonical_host(host: &str) -> String {
+    let host = host.trim();
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if !port.is_empty() && port.chars().all(|c| c.is_ascii_digit()) => name,
+        _ => host
+    };
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+pub fn check_host(host: &str) -> Result<(), String> {
+    let name = host.strip_prefix("*.").unwrap_or(host);
+    let valid = !name.is_empty() && name.split('.')
+        .all(|label| !label.is_empty() && label.len() <= 63 && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'));
+    if !valid {
+        return Err(format!("Host {host:?} has to be a hostname, optionally starting with \"*.\""));
+    }
+    Ok(())
+}
+
+/// `a.b.example.com`, `*.b.example.com`, `*.example.com`, `*.com`
+fn host_patterns(host: &str) -> Vec<String> {
+    let mut patterns = vec![host.to_owned()];
+    let mut rest = host;
+    while let Some((_, parent)) = rest.split_once('.') {
+        patterns.push(format!("*.{parent}"));
+        rest = parent;
+    }
+    patterns
+}
+
+/// Path routes, and path routes per host
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RouteTable<T> {
+    paths: PrefixTrie<T>,
+    hosts: HashMap<String, PrefixTrie<T>>,
+}
+
+impl<T> Default for RouteTable<T> {
+    fn default() -> Self {
+        Self { paths: PrefixTrie::default(), hosts: HashMap::new() }
+    }
+}
+
+impl<T> RouteTable<T> {
+    pub fn get(&self, route: &Route) -> Option<&T> {
+        match &route.host {
+            Some(host) => self.hosts.get(host)?.get(&route.prefix),
+            None => self.paths.get(&route.prefix)
+        }
+    }
+
+    pub fn get_mut(&mut self, route: &Route) -> Option<&mut T> {
+        match &route.host {
+            Some(host) => self.hosts.get_mut(host)?.get_mut(&route.prefix),
+            None => self.paths.get_mut(&route.prefix)
+        }
+    }
+
+    pub fn insert(&mut self, route: &Route, value: T) -> Option<T> {
+        let routes = match &route.host {
+            Some(host) => self.hosts.entry(host.clone()).or_default(),
+            None => &mut self.paths
+        };
+        routes.insert(&route.prefix, value)
+    }
+
+    pub fn remove(&mut self, route: &Route) -> Option<T> {
+        match &route.host {
+            Some(host) => {
+                let routes = self.hosts.get_mut(host)?;
+                let removed = routes.remove(&route.prefix);
+                if routes.is_empty() {
+                    self.hosts.remove(host);
+                }
+                removed
+            },
+            None => self.paths.remove(&route.prefix)
+        }
+    }
+
+    pub fn entries(&self) -> Vec<(Route, &T)> {
+        let hosts = self.hosts.iter()
+            .flat_map(|(host, routes)| routes.entries().into_iter()
+                .map(move |(prefix, value)| (Route { host: Some(host.clone()), prefix }, value)));
+        self.paths.entries().into_iter()
+            .map(|(prefix, value)| (Route { host: None, prefix }, value))
+            .chain(hosts)
+            .collect()
+    }
+
+    /// Routes of the exact host are tried first, then wildcard hosts from the most specific one, then the path routes
+    pub fn route(&self, host: Option<&str>, path: &str) -> Option<(Route, &T)> {
+        if let Some(host) = host.map(canonical_host) {
+            for pattern in host_patterns(&host) {
+                if let Some((prefix, value)) = self.hosts.get(&pattern).and_then(|routes| routes.longest_match(path)) {
+                    return Some((Route { host: Some(pattern), prefix }, value));
+                }
+            }
+        }
+
+        self.paths.longest_match(path)
+            .map(|(prefix, value)| (Route { host: None, prefix }, value))
+    }
+}
+
+/// How the request path is changed before it is passed to a service
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathRewrite {
+    /// removes the matched prefix, `/api/v1/users` is passed to the service at `api/v1` as `/users`
+    pub strip_prefix: bool,
+    /// replaces the path, `{prefix}` is the matched prefix and `{path}` the path after it, without leading slashes
+    pub rewrite: Option<String>,
+}
+
+/// The path after the first `segments` segments of `path`, keeping its leading slash
+fn strip_segments(path: &str, segments: usize) -> &str {
+    let mut rest = path;
+    for _ in 0..segments {
+        rest = rest.trim_start_matches('/');
+        rest = rest.find('/').map_or("", |index| &rest[index..]);
+    }
+    rest
+}
+
+impl PathRewrite {
+    pub fn is_identity(&self) -> bool {
+        !self.strip_prefix && self.rewrite.is_none()
+    }
+
+    pub fn check(&self) -> Result<(), String> {
+        match &self.rewrite {
+            Some(_) if self.strip_prefix => Err("Only one of strip_prefix and rewrite can be set".to_owned()),
+            Some(rewrite) if !rewrite.starts_with('/') => Err(format!("Rewrite {rewrite:?} has to start with /")),
+            Some(rewrite) if rewrite.replace("{prefix}", "").replace("{path}", "").contains(&['{', '}'][..]) =>
+                Err(format!("Rewrite {rewrite:?} can only contain the placeholders {{prefix}} and {{path}}")),
+            _ => Ok(())
+        }
+    }
+
+    /// The path passed to a service at `prefix`
+    pub fn apply(&self, prefix: &str, path: &str) -> String {
+        let segments = prefix.split('/').filter(|segment| !segment.is_empty()).count();
+        let rest = strip_segments(path, segments);
+        match &self.rewrite {
+            Some(rewrite) => rewrite
+                .replace("{prefix}", prefix)
+                .replace("{path}", rest.trim_start_matches('/')),
+            None if self.strip_prefix && rest.is_empty() => "/".to_owned(),
+            None if self.strip_prefix => rest.to_owned(),
+            None => path.to_owned()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(routes: &[(Option<&str>, &str)]) -> RouteTable<String> {
+        let mut table = RouteTable::default();
+        for (host, prefix) in routes {
+            let route = Route::new(*host, prefix);
+            table.insert(&route, route.to_string());
+        }
+        table
+    }
+
+    fn routed(table: &RouteTable<String>, host: Option<&str>, path: &str) -> Option<String> {
+        table.route(host, path).map(|(_, value)| value.clone())
+    }
+
+    #[test]
+    fn exact_hosts_take_precedence_over_wildcards() {
+        let table = table(&[(Some("users.example.com"), ""), (Some("*.example.com"), ""), (Some("*.com"), ""), (None, "")]);
+        assert_eq!(routed(&table, Some("users.example.com"), "/"), Some("users.example.com/".to_owned()));
+        assert_eq!(routed(&table, Some("orders.example.com"), "/"), Some("*.example.com/".to_owned()));
+        assert_eq!(routed(&table, Some("a.b.example.com"), "/"), Some("*.example.com/".to_owned()));
+        assert_eq!(routed(&table, Some("example.com"), "/"), Some("*.com/".to_owned()));
+        assert_eq!(routed(&table, Some("example.org"), "/"), Some("/".to_owned()));
+        assert_eq!(routed(&table, None, "/"), Some("/".to_owned()));
+    }
+
+    #[test]
+    fn wildcards_do_not_match_the_bare_domain() {
+        let table = table(&[(Some("*.example.com"), "")]);
+        assert_eq!(routed(&table, Some("example.com"), "/"), None);
+        assert_eq!(routed(&table, Some("notexample.com"), "/"), None);
+    }
+
+    #[test]
+    fn host_routes_fall_back_to_path_routes() {
+        let table = table(&[(Some("users.example.com"), "api"), (None, "api/v1")]);
+        assert_eq!(routed(&table, Some("users.example.com"), "/api/v1"), Some("users.example.com/api".to_owned()));
+        assert_eq!(routed(&table, Some("users.example.com"), "/other"), None);
+        assert_eq!(routed(&table, Some("orders.example.com"), "/api/v1/orders"), Some("/api/v1".to_owned()));
+    }
+
+    #[test]
+    fn ignores_port_case_and_trailing_dot_of_the_host() {
+        let table = table(&[(Some("Users.Example.com"), "")]);
+        for host in ["users.example.com:8080", "USERS.example.com", "users.example.com.", "users.example.com.:80"] {
+            assert_eq!(routed(&table, Some(host), "/"), Some("users.example.com/".to_owned()), "host {host:?}");
+        }
+        assert_eq!(canonical_host("[::1]:3000"), "[::1]");
+    }
+
+    #[test]
+    fn checks_hosts() {
+        assert!(check_host("users.example.com").is_ok());
+        assert!(check_host("*.example.com").is_ok());
+        assert!(check_host("users.*.com").is_err());
+        assert!(check_host("*.").is_err());
+        assert!(check_host("users..example.com").is_err());
+        assert!(check_host("users.example.com/api").is_err());
+    }
+
+    fn strip() -> PathRewrite {
+        PathRewrite { strip_prefix: true, rewrite: None }
+    }
+
+    fn rewrite(rewrite: &str) -> PathRewrite {
+        PathRewrite { strip_prefix: false, rewrite: Some(rewrite.to_owned()) }
+    }
+
+    #[test]
+    fn keeps_the_path_by_default() {
+        assert_eq!(PathRewrite::default().apply("api/v1", "/api/v1/users"), "/api/v1/users");
+    }
+
+    #[test]
+    fn strips_the_prefix() {
+        assert_eq!(strip().apply("api/v1", "/api/v1/users/1"), "/users/1");
+        assert_eq!(strip().apply("api/v1", "//api//v1/users"), "/users");
+        assert_eq!(strip().apply("api/v1", "/api/v1/users/"), "/users/");
+        assert_eq!(strip().apply("", "/users"), "/users");
+    }
+
+    #[test]
+    fn stripping_the_whole_path_leaves_the_root() {
+        assert_eq!(strip().apply("api/v1", "/api/v1"), "/");
+        assert_eq!(strip().apply("api/v1", "/api/v1/"), "/");
+        assert_eq!(strip().apply("", "/"), "/");
+    }
+
+    #[test]
+    fn substitutes_prefix_and_path() {
+        assert_eq!(rewrite("/v2/{path}").apply("api/v1", "/api/v1/users/1"), "/v2/users/1");
+        assert_eq!(rewrite("/internal/{prefix}/{path}").apply("api/v1", "/api/v1/users"), "/internal/api/v1/users");
+        assert_eq!(rewrite("/v2/{path}").apply("api/v1", "/api/v1"), "/v2/");
+        assert_eq!(rewrite("/static").apply("api", "/api/anything"), "/static");
+        assert_eq!(rewrite("/{path}/{path}").apply("", "/a/b"), "/a/b/a/b");
+    }
+
+    #[test]
+    fn checks_rewrites() {
+        assert!(strip().check().is_ok());
+        assert!(rewrite("/v2/{prefix}/{path}").check().is_ok());
+        assert!(PathRewrite { strip_prefix: true, rewrite: Some("/".to_owned()) }.check().is_err());
+        assert!(rewrite("v2/{path}").check().is_err());
+        assert!(rewrite("/v2/{query}").check().is_err());
+        assert!(rewrite("/v2/{path").check().is_err());
+    }
+}
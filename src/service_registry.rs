@@ -1,9 +1,10 @@
-use std::collections::HashMap;
+use std::{collections::{HashMap, HashSet}, time::{Duration, SystemTime}};
 
-use lunatic::{Process, Mailbox};
+use lunatic::{Process, Mailbox, Tag};
 use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
 
-use crate::{module_supervisor::{ModuleSupervisorMessage, self}};
+use crate::{module_supervisor::{ModuleSupervisorMessage, self}, router, config::{Config, Limits, Capabilities}};
 use frenezulo::{ ServiceId, RequestId, Request, Response, WorkerSerializer };
 
 type RespondTo = Process<Response>;
@@ -13,35 +14,118 @@ pub enum ServiceRegistryMessage {
     StartRequest(RequestId, ServiceId, Request, RespondTo),
     CancelRequest(RequestId, ServiceId),
     CompleteRequest(RequestId, ServiceId, Response),
-    AddService(ServiceId, lunatic_envelop::Envelop),
-    DeleteService(ServiceId)
+    AddService(ServiceId, ModuleInfo, Limits, Capabilities, lunatic_envelop::Envelop),
+    /// like AddService, but the router is told once the module compiled
+    StageService(ServiceId, ModuleInfo, Limits, Capabilities, lunatic_envelop::Envelop),
+    /// old service, new service
+    DrainService(ServiceId, ServiceId),
+    ServiceReady(ServiceId),
+    /// module hash, error
+    ModuleFailed(String, String),
+    DeleteService(ServiceId),
+    /// drained service, whose replacement is no longer needed to resolve requests routed before the swap
+    ForgetReplaced(ServiceId),
+    QueryServices(Vec<ServiceId>, Tag, Process<Vec<Option<ServiceInfo>>>)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ModuleInfo {
+    pub size: usize,
+    pub hash: String,
+}
+
+impl ModuleInfo {
+    pub fn new(module_data: &[u8]) -> Self {
+        Self { size: module_data.len(), hash: hex::encode(Sha256::digest(module_data)) }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServiceInfo {
+    pub service_id: ServiceId,
+    pub module: ModuleInfo,
+    /// seconds since the unix epoch
+    pub registered_at: u64,
+    pub outstanding_requests: usize,
+}
+
+enum ServiceState {
+    Compiling,
+    /// compiling, the router adds it to its route once compiled
+    Staged,
+    Serving,
+    /// replaced, killed once all outstanding requests completed
+    Draining,
+}
+
+struct Service {
+    requests: HashMap<RequestId, (Request, RespondTo)>,
+    module: ModuleInfo,
+    registered_at: SystemTime,
+    state: ServiceState,
+}
+
+/// A compiled module, shared by all services with the same module hash
+struct Module {
+    tag: Tag,
+    process: Process<ModuleSupervisorMessage, WorkerSerializer>,
+    services: HashSet<ServiceId>,
 }
 
 pub struct ServiceRegistry {
-    services: HashMap<ServiceId, (Process<ModuleSupervisorMessage, WorkerSerializer>, HashMap<RequestId, (Request, RespondTo)>)>
+    services: HashMap<ServiceId, Service>,
+    modules: HashMap<String, Module>,
+    /// replaced service -> replacement, for requests routed before the switch
+    replaced: HashMap<ServiceId, ServiceId>,
+    /// the most any service may be granted, module supervisors need access to all directories they may grant
+    allowed: Capabilities,
+    /// how long listeners may route with an outdated copy of the routes
+    route_cache: Duration,
+}
+
+fn error_response(request: &Request, status: u16, body: &[u8]) -> Response {
+    submillisecond::response::Response::builder()
+        .status(status)
+        .version(request.metadata.version.clone().into())
+        .body(body.to_vec())
+        .expect("Request Builder must succeed")
+        .into()
 }
 
 impl ServiceRegistry {
+    fn resolve(&self, mut service_id: ServiceId) -> ServiceId {
+        while let Some(replacement) = self.replaced.get(&service_id) {
+            service_id = *replacement;
+        }
+        service_id
+    }
+
     pub fn start_request(&mut self, service_id: ServiceId, request_id: RequestId, request: Request, respond_to: RespondTo) {
+        let service_id = self.resolve(service_id);
         match self.services.get_mut(&service_id) {
-            Some((process, requests)) => {
+            Some(Service { requests, module, .. }) => {
                 requests.insert(request_id, (request.clone(), respond_to));
                 
-                process.send(ModuleSupervisorMessage::StartRequest(request_id, request));
+                if let Some(Module { process, .. }) = self.modules.get(&module.hash) {
+                    process.send(ModuleSupervisorMessage::StartRequest(service_id, request_id, request));
+                }
             },
             None => {
+                // the service may have been deleted after the router handed out this id
                 println!("Invalid Service Id {service_id:?} Request: {request_id:?}");
-                panic!("Invalid Service Id")
+                respond_to.send(error_response(&request, 404, b"Service Deleted"));
             }
         }
     }
 
     pub fn cancel_request(&mut self, service_id: ServiceId, request_id: RequestId) {
         match self.services.get_mut(&service_id) {
-            Some((process, requests)) =>
+            Some(Service { requests, module, .. }) =>
                 match requests.remove(&request_id) {
                     Some((request, response_process)) => {
-                        process.send(ModuleSupervisorMessage::CancelRequest(request_id));
+                        if let Some(Module { process, .. }) = self.modules.get(&module.hash) {
+                            process.send(ModuleSupervisorMessage::CancelRequest(request_id));
+                        }
                         response_process.send(
                             submillisecond::response::Response::builder()
                                 .status(503)
@@ -59,39 +143,133 @@ impl ServiceRegistry {
         todo!("Send cancel response");
     }
 
-    pub fn add_service(&mut self, service_id: ServiceId, module_data: lunatic_envelop::Envelop) {
-        let worker = module_supervisor::start(
-            service_id.tag,
-            service_id,
-            module_data,
-            Process::this());
+    pub fn add_service(&mut self, service_id: ServiceId, module: ModuleInfo, limits: Limits, capabilities: Capabilities, module_data: lunatic_envelop::Envelop) {
+        if self.modules.contains_key(&module.hash) {
+            // compiled (or compiling) already, the bytes are not needed again
+            lunatic_envelop::open_envelop(module_data);
+        }
+        else {
+            let tag = Tag::new();
+            let process = module_supervisor::start(
+                tag,
+                module.hash.clone(),
+                module_data,
+                &self.allowed,
+                Process::this());
+            self.modules.insert(module.hash.clone(), Module { tag, process, services: HashSet::new() });
+        }
+
+        let shared = self.modules.get_mut(&module.hash).expect("module has been inserted");
+        shared.services.insert(service_id);
+        shared.process.send(ModuleSupervisorMessage::AttachService(service_id, limits, capabilities));
         
-        self.services.insert(service_id, (worker, HashMap::<RequestId, (Request, RespondTo)>::new()));
+        self.services.insert(service_id, Service {
+            requests: HashMap::new(),
+            module,
+            registered_at: SystemTime::now(),
+            state: ServiceState::Compiling
+        });
+    }
+
+    pub fn stage_service(&mut self, service_id: ServiceId, module: ModuleInfo, limits: Limits, capabilities: Capabilities, module_data: lunatic_envelop::Envelop) {
+        self.add_service(service_id, module, limits, capabilities, module_data);
+        if let Some(service) = self.services.get_mut(&service_id) {
+            service.state = ServiceState::Staged;
+        }
+    }
+
+    pub fn service_ready(&mut self, service_id: ServiceId) {
+        if let Some(service) = self.services.get_mut(&service_id) {
+            if let ServiceState::Staged = std::mem::replace(&mut service.state, ServiceState::Serving) {
+                router::activate_service(service_id);
+            }
+        }
+    }
+
+    pub fn module_failed(&mut self, module_hash: String, error: String) {
+        println!("Module {module_hash} failed to start: {error}");
+        let module = match self.modules.remove(&module_hash) {
+            Some(module) => module,
+            None => return
+        };
+
+        for service_id in module.services {
+            // routes of new services exist already, staged ones are only known to the router
+            if let Some(Service { state: ServiceState::Staged | ServiceState::Compiling, .. }) = self.services.get(&service_id) {
+                router::discard_service(service_id);
+            }
+            self.remove_service(service_id, 503, b"Service failed to start");
+        }
+    }
+
+    pub fn drain_service(&mut self, service_id: ServiceId, replacement: ServiceId) {
+        self.replaced.insert(service_id, replacement);
+        let drained = match self.services.get_mut(&service_id) {
+            Some(service) => {
+                service.state = ServiceState::Draining;
+                service.requests.is_empty()
+            },
+            None => false
+        };
+
+        if drained {
+            self.drained(service_id);
+        }
+    }
+
+    /// Removes the drained service, requests routed to it are still forwarded to its replacement until
+    /// every listener refreshed its routes
+    fn drained(&mut self, service_id: ServiceId) {
+        println!("Drained service {service_id:?}");
+        self.remove_service(service_id, 404, b"Service Deleted");
+        Process::<ServiceRegistryMessage>::this().send_after(ServiceRegistryMessage::ForgetReplaced(service_id), self.route_cache);
+    }
+
+    pub fn forget_replaced(&mut self, service_id: ServiceId) {
+        self.replaced.remove(&service_id);
     }
 
     pub fn delete_service(&mut self, service_id: ServiceId) {
+        self.replaced.retain(|_, replacement| *replacement != service_id);
+        self.remove_service(service_id, 404, b"Service Deleted");
+    }
+
+    fn remove_service(&mut self, service_id: ServiceId, status: u16, body: &[u8]) {
         match self.services.remove(&service_id) {
-            Some((worker, mut table)) => {
-                worker.kill();
-                table.drain()
+            Some(Service { module, mut requests, .. }) => {
+                self.detach(service_id, &module.hash);
+                requests.drain()
                     .for_each(|(_id, (request, process))| {
-                        process.send(
-                            submillisecond::response::Response::builder()
-                                .status(404)
-                                .version(request.metadata.version.into())
-                                .body(b"Service Deleted".to_vec())
-                                .expect("Request Builder must succeed")
-                                .into());
+                        process.send(error_response(&request, status, body));
                     });
             }
             None => (),
         }
     }
 
+    /// Detaches the service from its module, the module is unloaded once no service uses it anymore
+    fn detach(&mut self, service_id: ServiceId, module_hash: &str) {
+        let unused = match self.modules.get_mut(module_hash) {
+            Some(module) => {
+                module.services.remove(&service_id);
+                module.process.send(ModuleSupervisorMessage::DetachService(service_id));
+                module.services.is_empty()
+            },
+            None => false
+        };
+
+        if unused {
+            if let Some(module) = self.modules.remove(module_hash) {
+                println!("Unloading module {module_hash}");
+                module.process.kill();
+            }
+        }
+    }
+
     pub fn complete_request(&mut self, request_id: RequestId, service_id: ServiceId, response: Response) {
         match self.services.get_mut(&service_id) {
-            Some((_worker, table)) => {
-                match table.remove(&request_id) {
+            Some(Service { requests, .. }) => {
+                match requests.remove(&request_id) {
                     Some((_request_id, respond_to)) => {
                         respond_to.send(response);
                     },
@@ -100,16 +278,42 @@ impl ServiceRegistry {
             },
             None => ()
         }
+
+        if let Some(Service { state: ServiceState::Draining, requests, .. }) = self.services.get(&service_id) {
+            if requests.is_empty() {
+                self.drained(service_id);
+            }
+        }
+    }
+
+    pub fn query_services(&self, service_ids: Vec<ServiceId>, tag: Tag, respond_to: Process<Vec<Option<ServiceInfo>>>) {
+        let infos = service_ids.into_iter()
+            .map(|service_id| {
+                self.services.get(&service_id).map(|service| ServiceInfo {
+                    service_id,
+                    module: service.module.clone(),
+                    registered_at: service.registered_at
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .map_or(0, |d| d.as_secs()),
+                    outstanding_requests: service.requests.len()
+                })
+            })
+            .collect();
+        respond_to.tag_send(tag, infos);
     }
 }
 
-pub fn start() -> Process<ServiceRegistryMessage> {
-    Process::spawn_link((), |(), mailbox: Mailbox<ServiceRegistryMessage>| {
+pub fn start(config: Config) -> Process<ServiceRegistryMessage> {
+    Process::spawn_link(config, |config, mailbox: Mailbox<ServiceRegistryMessage>| {
         println!("service registry started");
         mailbox.this().register("service_registry");
         println!("service registry registered");
         let mut instance = ServiceRegistry {
-            services: HashMap::new()
+            services: HashMap::new(),
+            modules: HashMap::new(),
+            replaced: HashMap::new(),
+            allowed: config.capabilities,
+            route_cache: Duration::from_millis(config.server.route_cache_ms)
         };
 
         let mailbox = mailbox.catch_link_failure();
@@ -124,14 +328,31 @@ pub fn start() -> Process<ServiceRegistryMessage> {
                         instance.cancel_request(service_id, request_id),
                     ServiceRegistryMessage::CompleteRequest(request_id, service_id, response) =>
                         instance.complete_request(request_id, service_id, response),
-                    ServiceRegistryMessage::AddService(service_id, module_data) =>
-                        instance.add_service(service_id, module_data),
+                    ServiceRegistryMessage::AddService(service_id, module, limits, capabilities, module_data) =>
+                        instance.add_service(service_id, module, limits, capabilities, module_data),
+                    ServiceRegistryMessage::StageService(service_id, module, limits, capabilities, module_data) =>
+                        instance.stage_service(service_id, module, limits, capabilities, module_data),
+                    ServiceRegistryMessage::DrainService(service_id, replacement) =>
+                        instance.drain_service(service_id, replacement),
+                    ServiceRegistryMessage::ServiceReady(service_id) =>
+                        instance.service_ready(service_id),
+                    ServiceRegistryMessage::ModuleFailed(module_hash, error) =>
+                        instance.module_failed(module_hash, error),
                     ServiceRegistryMessage::DeleteService(service_id) =>
                         instance.delete_service(service_id),
+                    ServiceRegistryMessage::ForgetReplaced(service_id) =>
+                        instance.forget_replaced(service_id),
+                    ServiceRegistryMessage::QueryServices(service_ids, tag, respond_to) =>
+                        instance.query_services(service_ids, tag, respond_to),
                 },
                 lunatic::MailboxResult::DeserializationFailed(_) => todo!(),
                 lunatic::MailboxResult::TimedOut => todo!(),
-                lunatic::MailboxResult::LinkDied(_) => todo!("handle module supervisor crashes"), // Tag == service_id.tag
+                lunatic::MailboxResult::LinkDied(tag) => {
+                    // supervisors of unused modules are killed on purpose
+                    if instance.modules.values().any(|module| module.tag == tag) {
+                        todo!("handle module supervisor crashes")
+                    }
+                },
             }
         }
     })
@@ -149,14 +370,35 @@ pub fn cancel_request(request_id: RequestId, service_id: ServiceId) {
         .send(ServiceRegistryMessage::CancelRequest(request_id, service_id))
 }
 
-pub fn add_service(service_id: ServiceId, module_data: Vec<u8>) {
+pub fn add_service(service_id: ServiceId, module: ModuleInfo, limits: Limits, capabilities: Capabilities, module_data: Vec<u8>) {
+    Process::<ServiceRegistryMessage>::lookup("service_registry")
+        .expect("service registry has to be online")
+        .send(ServiceRegistryMessage::AddService(service_id, module, limits, capabilities, lunatic_envelop::create_envelop(module_data)))
+}
+
+pub fn stage_service(service_id: ServiceId, module: ModuleInfo, limits: Limits, capabilities: Capabilities, module_data: Vec<u8>) {
+    Process::<ServiceRegistryMessage>::lookup("service_registry")
+        .expect("service registry has to be online")
+        .send(ServiceRegistryMessage::StageService(service_id, module, limits, capabilities, lunatic_envelop::create_envelop(module_data)))
+}
+
+pub fn drain_service(service_id: ServiceId, replacement: ServiceId) {
     Process::<ServiceRegistryMessage>::lookup("service_registry")
         .expect("service registry has to be online")
-        .send(ServiceRegistryMessage::AddService(service_id, lunatic_envelop::create_envelop(module_data)))
+        .send(ServiceRegistryMessage::DrainService(service_id, replacement))
 }
 
 pub fn delete_service(service_id: ServiceId) {
     Process::<ServiceRegistryMessage>::lookup("service_registry")
         .expect("service registry has to be online")
         .send(ServiceRegistryMessage::DeleteService(service_id))
+}
+
+pub fn query_services(service_ids: Vec<ServiceId>) -> Vec<Option<ServiceInfo>> {
+    let tag = Tag::new();
+    let mailbox : Mailbox<Vec<Option<ServiceInfo>>> = unsafe { Mailbox::new() };
+    Process::<ServiceRegistryMessage>::lookup("service_registry")
+        .expect("service registry has to be online")
+        .send(ServiceRegistryMessage::QueryServices(service_ids, tag, Process::this()));
+    mailbox.tag_receive(Some(&[tag]))
 }
\ No newline at end of file
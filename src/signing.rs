@@ -0,0 +1,24 @@
+use anyhow::{anyhow, Context};
+use ed25519_dalek::{PublicKey, Signature};
+
+/// Parses a hex encoded Ed25519 public key
+pub fn parse_key(key: &str) -> anyhow::Result<PublicKey> {
+    let bytes = hex::decode(key.trim()).context("public key is not valid hex")?;
+    PublicKey::from_bytes(&bytes).map_err(|e| anyhow!("invalid public key: {e}"))
+}
+
+/// Checks the detached signature over `module_data` against all trusted keys
+pub fn verify(trusted_keys: &[PublicKey], module_data: &[u8], signature: Option<&[u8]>) -> Result<(), String> {
+    if trusted_keys.is_empty() {
+        return Ok(());
+    }
+
+    let signature = signature.ok_or_else(|| "Module is not signed".to_owned())?;
+    let signature = Signature::try_from(signature).map_err(|e| format!("Invalid module signature: {e}"))?;
+    if trusted_keys.iter().any(|key| key.verify_strict(module_data, &signature).is_ok()) {
+        Ok(())
+    }
+    else {
+        Err("Module signature does not match any trusted key".to_owned())
+    }
+}
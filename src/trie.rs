@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+
+use serde::{Serialize, Deserialize};
+
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|segment| !segment.is_empty())
+}
+
+/// `prefix` without leading, trailing or duplicate slashes, as it is stored in the trie
+pub fn canonical(prefix: &str) -> String {
+    segments(prefix).collect::<Vec<_>>().join("/")
+}
+
+/// Values keyed by path prefixes, split into segments so `/api/v1` never matches `/api/v10`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PrefixTrie<T> {
+    value: Option<T>,
+    children: HashMap<String, PrefixTrie<T>>,
+}
+
+impl<T> Default for PrefixTrie<T> {
+    fn default() -> Self {
+        Self { value: None, children: HashMap::new() }
+    }
+}
+
+impl<T> PrefixTrie<T> {
+    pub fn get(&self, prefix: &str) -> Option<&T> {
+        let mut node = self;
+        for segment in segments(prefix) {
+            node = node.children.get(segment)?;
+        }
+        node.value.as_ref()
+    }
+
+    pub fn get_mut(&mut self, prefix: &str) -> Option<&mut T> {
+        let mut node = self;
+        for segment in segments(prefix) {
+            node = node.children.get_mut(segment)?;
+        }
+        node.value.as_mut()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.value.is_none() && self.children.is_empty()
+    }
+
+    pub fn insert(&mut self, prefix: &str, value: T) -> Option<T> {
+        let mut node = self;
+        for segment in segments(prefix) {
+            node = node.children.entry(segment.to_owned()).or_default();
+        }
+        node.value.replace(value)
+    }
+
+    pub fn remove(&mut self, prefix: &str) -> Option<T> {
+        self.remove_segments(&segments(prefix).collect::<Vec<_>>())
+    }
+
+    fn remove_segments(&mut self, segments: &[&str]) -> Option<T> {
+        match segments.split_first() {
+            None => self.value.take(),
+            Some((segment, rest)) => {
+                let child = self.children.get_mut(*segment)?;
+                let removed = child.remove_segments(rest);
+                // drop branches without values
+                if child.value.is_none() && child.children.is_empty() {
+                    self.children.remove(*segment);
+                }
+                removed
+            }
+        }
+    }
+
+    /// The value of the longest prefix of `path`, together with that prefix
+    pub fn longest_match(&self, path: &str) -> Option<(String, &T)> {
+        let segments = segments(path).collect::<Vec<_>>();
+        let mut node = self;
+        let mut best = node.value.as_ref().map(|value| (0, value));
+        for (index, segment) in segments.iter().enumerate() {
+            node = match node.children.get(*segment) {
+                Some(child) => child,
+                None => break
+            };
+            if let Some(value) = &node.value {
+                best = Some((index + 1, value));
+            }
+        }
+        best.map(|(len, value)| (segments[..len].join("/"), value))
+    }
+
+    /// All values with their prefixes
+    pub fn entries(&self) -> Vec<(String, &T)> {
+        let mut entries = vec![];
+        self.collect("", &mut entries);
+        entries
+    }
+
+    fn collect<'a>(&'a self, prefix: &str, entries: &mut Vec<(String, &'a T)>) {
+        if let Some(value) = &self.value {
+            entries.push((prefix.to_owned(), value));
+        }
+        for (segment, child) in &self.children {
+            let child_prefix = if prefix.is_empty() { segment.clone() } else { format!("{prefix}/{segment}") };
+            child.collect(&child_prefix, entries);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trie(prefixes: &[&str]) -> PrefixTrie<String> {
+        let mut trie = PrefixTrie::default();
+        for prefix in prefixes {
+            trie.insert(prefix, prefix.to_string());
+        }
+        trie
+    }
+
+    fn matched(trie: &PrefixTrie<String>, path: &str) -> Option<String> {
+        trie.longest_match(path).map(|(prefix, _)| prefix)
+    }
+
+    #[test]
+    fn matches_whole_segments_only() {
+        let trie = trie(&["api/v1"]);
+        assert_eq!(matched(&trie, "/api/v1"), Some("api/v1".to_owned()));
+        assert_eq!(matched(&trie, "/api/v1/users"), Some("api/v1".to_owned()));
+        assert_eq!(matched(&trie, "/api/v10"), None);
+        assert_eq!(matched(&trie, "/api/v10/users"), None);
+        assert_eq!(matched(&trie, "/api"), None);
+    }
+
+    #[test]
+    fn prefers_the_longest_prefix() {
+        let trie = trie(&["api", "api/v1", "api/v1/admin"]);
+        assert_eq!(matched(&trie, "/api/v1/admin/users"), Some("api/v1/admin".to_owned()));
+        assert_eq!(matched(&trie, "/api/v1/users"), Some("api/v1".to_owned()));
+        assert_eq!(matched(&trie, "/api/v2"), Some("api".to_owned()));
+        assert_eq!(matched(&trie, "/other"), None);
+    }
+
+    #[test]
+    fn empty_prefix_matches_everything() {
+        let trie = trie(&["", "api"]);
+        assert_eq!(matched(&trie, "/"), Some("".to_owned()));
+        assert_eq!(matched(&trie, "/other/path"), Some("".to_owned()));
+        assert_eq!(matched(&trie, "/api/v1"), Some("api".to_owned()));
+    }
+
+    #[test]
+    fn ignores_duplicate_and_trailing_slashes() {
+        let trie = trie(&["/api//v1/"]);
+        assert_eq!(trie.get("api/v1"), Some(&"/api//v1/".to_owned()));
+        assert_eq!(matched(&trie, "//api/v1//users"), Some("api/v1".to_owned()));
+        assert_eq!(canonical("//api//v1/"), "api/v1");
+    }
+
+    #[test]
+    fn remove_drops_empty_branches() {
+        let mut trie = trie(&["api/v1/admin", "api"]);
+        assert_eq!(trie.remove("api/v1/admin"), Some("api/v1/admin".to_owned()));
+        assert_eq!(trie.remove("api/v1"), None);
+        assert_eq!(matched(&trie, "/api/v1/admin"), Some("api".to_owned()));
+        assert_eq!(trie.remove("api"), Some("api".to_owned()));
+        assert!(trie.is_empty());
+    }
+}
@@ -0,0 +1,212 @@
+use anyhow::{anyhow, bail};
+use frenezulo::{Manifest, MANIFEST_SECTION};
+use lunatic::WasmModule;
+use submillisecond::http::Method;
+
+use crate::{trie, config::CAPABILITY_NAMES};
+
+/// Export every service module has to have, see frenezulo-macros
+pub static ENTRY_POINT : &str = "frenezulo_main";
+
+static CUSTOM_SECTION : u8 = 0;
+static EXPORT_SECTION : u8 = 7;
+static FUNCTION_EXPORT : u8 = 0;
+
+/// Minimal reader for the parts of the wasm binary format we need
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> anyhow::Result<u8> {
+        let (byte, rest) = self.data.split_first().ok_or_else(|| anyhow!("unexpected end of module"))?;
+        self.data = rest;
+        Ok(*byte)
+    }
+
+    fn bytes(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        if self.data.len() < len {
+            bail!("unexpected end of module");
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    /// unsigned LEB128
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        let mut result : u32 = 0;
+        for shift in (0..35).step_by(7) {
+            let byte = self.byte()?;
+            result |= ((byte & 0x7f) as u32).checked_shl(shift).unwrap_or(0);
+            if byte & 0x80 == 0 {
+                return Ok(result);
+            }
+        }
+        bail!("invalid LEB128 integer")
+    }
+
+    fn name(&mut self) -> anyhow::Result<&'a str> {
+        let len = self.u32()? as usize;
+        std::str::from_utf8(self.bytes(len)?).map_err(|_| anyhow!("invalid UTF-8 name"))
+    }
+}
+
+/// Sections of the module as (id, contents)
+fn sections(module_data: &[u8]) -> anyhow::Result<Vec<(u8, &[u8])>> {
+    let mut reader = Reader { data: module_data };
+    if reader.bytes(8).ok() != Some(&b"\0asm\x01\0\0\0"[..]) {
+        bail!("not a wasm module");
+    }
+
+    let mut sections = vec![];
+    while !reader.data.is_empty() {
+        let id = reader.byte()?;
+        let len = reader.u32()? as usize;
+        sections.push((id, reader.bytes(len)?));
+    }
+    Ok(sections)
+}
+
+/// Names of all exported functions
+pub fn function_exports(module_data: &[u8]) -> anyhow::Result<Vec<String>> {
+    let mut exports = vec![];
+    for (_, section) in sections(module_data)?.into_iter().filter(|(id, _)| *id == EXPORT_SECTION) {
+        let mut reader = Reader { data: section };
+        for _ in 0..reader.u32()? {
+            let name = reader.name()?;
+            let kind = reader.byte()?;
+            reader.u32()?;
+            if kind == FUNCTION_EXPORT {
+                exports.push(name.to_owned());
+            }
+        }
+    }
+    Ok(exports)
+}
+
+/// The embedded manifest, or the default manifest if the module has none
+pub fn manifest(module_data: &[u8]) -> anyhow::Result<Manifest> {
+    for (_, section) in sections(module_data)?.into_iter().filter(|(id, _)| *id == CUSTOM_SECTION) {
+        let mut reader = Reader { data: section };
+        if reader.name()? == MANIFEST_SECTION {
+            let mut manifest : Manifest = serde_json::from_slice(reader.data).map_err(|e| anyhow!("invalid manifest: {e}"))?;
+            // requests are matched against the uppercase method names
+            manifest.methods.iter_mut().for_each(|method| method.make_ascii_uppercase());
+            return Ok(manifest);
+        }
+    }
+    Ok(Manifest::default())
+}
+
+fn validate_manifest(manifest: &Manifest) -> anyhow::Result<()> {
+    if let Some(prefix) = &manifest.prefix {
+        if trie::canonical(prefix).is_empty() {
+            bail!("manifest prefix {prefix:?} has to contain at least one path segment");
+        }
+    }
+    if [manifest.timeout_ms, manifest.max_memory, manifest.max_fuel, manifest.max_requests, manifest.max_lifetime_ms].contains(&Some(0)) {
+        bail!("manifest limits have to be above 0");
+    }
+    if let Some(method) = manifest.methods.iter().find(|method| Method::from_bytes(method.as_bytes()).is_err()) {
+        bail!("manifest method {method:?} is not a valid HTTP method");
+    }
+    if let Some(name) = manifest.capabilities.iter().find(|name| !CAPABILITY_NAMES.contains(&name.as_str())) {
+        bail!("manifest capability {name:?} is unknown, expected one of {}", CAPABILITY_NAMES.join(", "));
+    }
+    Ok(())
+}
+
+/// Checks the module can be used as a service without compiling it, returns its manifest
+pub fn validate(module_data: &[u8]) -> anyhow::Result<Manifest> {
+    let exports = function_exports(module_data).map_err(|e| anyhow!("Invalid module: {e}"))?;
+    if !exports.iter().any(|export| export == ENTRY_POINT) {
+        bail!("Invalid module: no {ENTRY_POINT} function is exported");
+    }
+    let manifest = manifest(module_data).map_err(|e| anyhow!("Invalid module: {e}"))?;
+    validate_manifest(&manifest).map_err(|e| anyhow!("Invalid module: {e}"))?;
+    Ok(manifest)
+}
+
+/// Compiles the module to catch errors the checks of `validate` can't, the compiled module is dropped.
+/// Only needed once per distinct module, modules that are loaded already compiled.
+pub fn compile(module_data: &[u8]) -> anyhow::Result<()> {
+    WasmModule::new(module_data).map_err(|e| anyhow!("Failed to compile module: {e:?}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static HEADER : &[u8] = b"\0asm\x01\0\0\0";
+
+    fn module(sections: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut module = HEADER.to_vec();
+        for (id, contents) in sections {
+            module.push(*id);
+            module.push(contents.len() as u8);
+            module.extend_from_slice(contents);
+        }
+        module
+    }
+
+    #[test]
+    fn reads_leb128() {
+        assert_eq!(Reader { data: &[0x00] }.u32().unwrap(), 0);
+        assert_eq!(Reader { data: &[0xe5, 0x8e, 0x26] }.u32().unwrap(), 624485);
+        assert_eq!(Reader { data: &[0xff, 0xff, 0xff, 0xff, 0x0f] }.u32().unwrap(), u32::MAX);
+        // the continuation bit promises more bytes than there are
+        assert!(Reader { data: &[0x80, 0x80] }.u32().is_err());
+        // more than 5 bytes can't be a u32
+        assert!(Reader { data: &[0x80, 0x80, 0x80, 0x80, 0x80, 0x00] }.u32().is_err());
+    }
+
+    #[test]
+    fn rejects_lengths_beyond_the_data() {
+        let mut reader = Reader { data: b"\x05abc" };
+        assert!(reader.name().is_err());
+        let mut reader = Reader { data: b"abc" };
+        assert!(reader.bytes(4).is_err());
+        assert_eq!(reader.bytes(3).unwrap(), b"abc");
+        assert!(reader.byte().is_err());
+        let mut reader = Reader { data: b"\x02\xff\xfe" };
+        assert!(reader.name().is_err());
+    }
+
+    #[test]
+    fn splits_sections() {
+        let module = module(&[(1, &b"\x00"[..]), (CUSTOM_SECTION, &b"\x01ab"[..])]);
+        assert_eq!(sections(&module).unwrap(), vec![(1, &b"\x00"[..]), (CUSTOM_SECTION, &b"\x01ab"[..])]);
+        assert!(sections(b"\0asm\x02\0\0\0").is_err());
+        assert!(sections(&HEADER[..4]).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_modules() {
+        let module = module(&[(EXPORT_SECTION, &b"\x01\x0efrenezulo_main\x00\x00"[..])]);
+        assert_eq!(function_exports(&module).unwrap(), vec![ENTRY_POINT.to_owned()]);
+        for len in HEADER.len() + 1..module.len() {
+            assert!(function_exports(&module[..len]).is_err(), "accepted module truncated to {len} bytes");
+        }
+    }
+
+    #[test]
+    fn rejects_sections_longer_than_the_module() {
+        let mut module = module(&[(CUSTOM_SECTION, &b"\x01a"[..])]);
+        module[HEADER.len() + 1] = 0x7f;
+        assert!(sections(&module).is_err());
+        assert!(manifest(&module).is_err());
+    }
+
+    #[test]
+    fn reads_the_manifest_section() {
+        let mut contents = vec![MANIFEST_SECTION.len() as u8];
+        contents.extend_from_slice(MANIFEST_SECTION.as_bytes());
+        contents.extend_from_slice(br#"{"prefix":"hello","timeout_ms":100}"#);
+        let parsed = manifest(&module(&[(CUSTOM_SECTION, &contents[..])])).unwrap();
+        assert_eq!(parsed.prefix.as_deref(), Some("hello"));
+        assert_eq!(parsed.timeout_ms, Some(100));
+        assert_eq!(manifest(HEADER).unwrap(), Manifest::default());
+    }
+}